@@ -0,0 +1,160 @@
+#!/usr/bin/env python3
+"""Generate the syscall name tables of kernel-delay/src/syscalls from the Linux UAPI headers.
+
+Usage: build/gen-syscalls.py [INCLUDE_DIR]
+
+INCLUDE_DIR defaults to /usr/include and must hold asm-generic/unistd.h and the x86_64
+asm/unistd_64.h (as installed by linux-libc-dev, or `make headers_install` of a kernel tree).
+aarch64, riscv64 and every other architecture using the generic syscall table share
+asm-generic/unistd.h, each with its own set of __ARCH_WANT_* options.
+"""
+
+import os
+import re
+import subprocess
+import sys
+import tempfile
+
+OUT_DIR = os.path.join(os.path.dirname(__file__), "..", "kernel-delay", "src", "syscalls")
+
+# The __ARCH_WANT_* options of arch/<arch>/include/uapi/asm/unistd.h
+GENERIC_OPTIONS = {
+    "aarch64": [
+        "__ARCH_WANT_RENAMEAT",
+        "__ARCH_WANT_NEW_STAT",
+        "__ARCH_WANT_SET_GET_RLIMIT",
+        "__ARCH_WANT_SYS_CLONE3",
+        "__ARCH_WANT_MEMFD_SECRET",
+    ],
+    "riscv64": [
+        "__ARCH_WANT_NEW_STAT",
+        "__ARCH_WANT_SET_GET_RLIMIT",
+        "__ARCH_WANT_SYS_CLONE3",
+        "__ARCH_WANT_MEMFD_SECRET",
+    ],
+    # Anything else using the generic table: every optional syscall, a name is better than none
+    "generic": [
+        "__ARCH_WANT_RENAMEAT",
+        "__ARCH_WANT_NEW_STAT",
+        "__ARCH_WANT_SET_GET_RLIMIT",
+        "__ARCH_WANT_SYS_CLONE3",
+        "__ARCH_WANT_MEMFD_SECRET",
+    ],
+}
+
+# Syscalls in the range the generic table leaves to the architectures (__NR_arch_specific_syscall)
+ARCH_SPECIFIC = {
+    "riscv64": {"riscv_hwprobe": 258, "riscv_flush_icache": 259},
+}
+
+# Syscalls added after the installed headers may be, numbers from 424 on are the same on every
+# architecture. Only used when the headers don't have them.
+UNIFIED = {
+    "cachestat": 451,
+    "fchmodat2": 452,
+    "map_shadow_stack": 453,
+    "futex_wake": 454,
+    "futex_wait": 455,
+    "futex_requeue": 456,
+    "statmount": 457,
+    "listmount": 458,
+    "lsm_get_self_attr": 459,
+    "lsm_set_self_attr": 460,
+    "lsm_list_modules": 461,
+    "mseal": 462,
+    "setxattrat": 463,
+    "getxattrat": 464,
+    "listxattrat": 465,
+    "removexattrat": 466,
+    "open_tree_attr": 467,
+    "file_getattr": 468,
+    "file_setattr": 469,
+}
+
+# Architecture specific syscalls added after the installed headers may be, only used when the
+# headers don't have them either
+NEWER = {
+    "x86_64": {"uretprobe": 335},
+}
+
+# __NR_ macros that are not syscalls
+NOT_SYSCALLS = {"syscalls", "arch_specific_syscall"}
+
+
+def macros(header, include_dir, options=()):
+    """All macros defined by `header` on a 64-bit architecture, as name -> body."""
+    with tempfile.TemporaryDirectory() as stub_dir:
+        # asm/bitsperlong.h is per architecture, and may not be installed for this one
+        os.makedirs(os.path.join(stub_dir, "asm"))
+        open(os.path.join(stub_dir, "asm", "bitsperlong.h"), "w").close()
+        command = ["cpp", "-dM", "-nostdinc", "-I", stub_dir, "-I", include_dir]
+        command += ["-D__BITS_PER_LONG=64"] + ["-D" + option for option in options]
+        output = subprocess.run(
+            command + [header], check=True, capture_output=True, text=True
+        ).stdout
+    defines = {}
+    for line in output.splitlines():
+        match = re.match(r"#define (\w+) (.+)", line)
+        if match:
+            defines[match.group(1)] = match.group(2).strip()
+    return defines
+
+
+def evaluate(body, defines):
+    """Value of a macro body made of numbers, other macros, parentheses and + (e.g.
+    `(__NR_arch_specific_syscall + 15)` or `__NR3264_fcntl`)."""
+    expression = re.sub(
+        r"[A-Za-z_]\w*", lambda m: str(evaluate(defines[m.group(0)], defines)), body
+    )
+    if not re.fullmatch(r"[\d\s()+]+", expression):
+        raise ValueError(f"unexpected macro body {body!r}")
+    return eval(expression)
+
+
+def syscalls(defines):
+    table = {}
+    for name, body in defines.items():
+        match = re.fullmatch(r"__NR_(\w+)", name)
+        if match and match.group(1) not in NOT_SYSCALLS:
+            table[match.group(1)] = evaluate(body, defines)
+    return table
+
+
+def write(arch, table, source):
+    for name, number in [*NEWER.get(arch, {}).items(), *UNIFIED.items()]:
+        if name not in table and number not in table.values():
+            table[name] = number
+    rows = sorted((number, name) for name, number in table.items())
+    path = os.path.join(OUT_DIR, f"{arch}.rs")
+    with open(path, "w") as out:
+        out.write(f"// Generated by build/gen-syscalls.py from {source}, do not edit.\n\n")
+        described = "other architectures using the generic table" if arch == "generic" else arch
+        out.write(f"/// Syscall names of {described}, sorted by number.\n")
+        out.write("pub const SYSCALLS: &[(u32, &str)] = &[\n")
+        for number, name in rows:
+            out.write(f'    ({number}, "{name}"),\n')
+        out.write("];\n")
+    print(f"{path}: {len(rows)} syscalls")
+
+
+def main():
+    include_dir = sys.argv[1] if len(sys.argv) > 1 else "/usr/include"
+
+    # x86_64 has its own table, installed as asm/unistd_64.h (multiarch directory on Debian)
+    candidates = [
+        os.path.join(include_dir, "asm", "unistd_64.h"),
+        os.path.join(include_dir, "x86_64-linux-gnu", "asm", "unistd_64.h"),
+    ]
+    header = next(path for path in candidates if os.path.exists(path))
+    table = syscalls(macros(header, include_dir))
+    write("x86_64", table, "asm/unistd_64.h")
+
+    generic_header = os.path.join(include_dir, "asm-generic", "unistd.h")
+    for arch, options in GENERIC_OPTIONS.items():
+        table = syscalls(macros(generic_header, include_dir, options))
+        table.update(ARCH_SPECIFIC.get(arch, {}))
+        write(arch, table, "asm-generic/unistd.h")
+
+
+if __name__ == "__main__":
+    main()
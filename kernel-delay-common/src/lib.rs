@@ -12,17 +12,19 @@ pub enum EventType {
     ThreadReadyStats = 5,
     HardIrqStats = 6,
     SoftIrqStats = 7,
+    SyscallErrorStats = 8, // Only in STATS: the failed syscalls, per errno
 }
 
 // Event structure for syscall statistics
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct SyscallStat {
-    pub name: [u8; 16],
+    pub name: [u8; 16], // Unused, syscalls are named by userspace from `number`
     pub number: u32,
     pub count: u32,
     pub total_ns: u64,
     pub max_ns: u64,
+    pub ret: i64, // Return value, -errno on failure
 }
 
 // Event structure for thread run statistics
@@ -55,12 +57,17 @@ pub struct IrqStat {
     pub vector: u32, // For soft IRQs
 }
 
+// Layout version of Event (and the structs it embeds), stored in recordings. Bump whenever a
+// field is added, removed or changes meaning.
+pub const EVENT_VERSION: u32 = 3;
+
 // Main event structure
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct Event {
     pub timestamp: u64,
     pub tid: u32,
+    pub pid: u32,
     pub thread_name: [u8; 16],
     pub resource_type: [u8; 32],
     pub event_type: u32,
@@ -70,6 +77,170 @@ pub struct Event {
     pub thread_ready_stat: ThreadReadyStat,
     pub irq_stat: IrqStat,
     pub total_excluding_poll: u64,
+    // Ids in the STACKS map of the kernel and user stacks, negative when not captured
+    pub kernel_stack_id: i32,
+    pub user_stack_id: i32,
+}
+
+// Tracepoint field offsets, parsed by userspace from tracefs so that the eBPF programs don't
+// depend on the layout of a particular kernel version or architecture
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FieldOffsets {
+    pub syscall_nr: u32,          // syscalls:sys_{enter,exit}_* `__syscall_nr`
+    pub syscall_ret: u32,         // syscalls:sys_exit_* `ret`
+    pub raw_syscall_id: u32,      // raw_syscalls:sys_{enter,exit} `id`
+    pub raw_syscall_ret: u32,     // raw_syscalls:sys_exit `ret`
+    pub softirq_vec: u32,         // irq:softirq_{entry,exit} `vec`
+    pub sched_prev_pid: u32,      // sched:sched_switch `prev_pid`
+    pub sched_prev_state: u32,    // sched:sched_switch `prev_state`
+    pub sched_next_pid: u32,      // sched:sched_switch `next_pid`
+    pub sched_wakeup_pid: u32,    // sched:sched_wakeup{,_new} `pid`
+    pub irq_handler_irq: u32,     // irq:irq_handler_{entry,exit} `irq`
+    pub irq_handler_name: u32,    // irq:irq_handler_entry `name` (__data_loc)
+    pub newtask_pid: u32,         // task:task_newtask `pid`
+    pub newtask_clone_flags: u32, // task:task_newtask `clone_flags`
+    pub newtask_comm: u32,        // task:task_newtask `comm`
+    pub exit_pid: u32,            // sched:sched_process_exit `pid`
+    pub task_rename_pid: u32,     // task:task_rename `pid`, 0 on kernels without it
+    pub task_rename_newcomm: u32, // task:task_rename `newcomm`
+}
+
+// Config::flags: accumulate count/total/min/max per (pid, tid, kind, id) in the STATS map
+pub const FLAG_AGGREGATE: u32 = 1 << 0;
+// Config::flags: stream one Event per measurement through RING_BUF
+pub const FLAG_TRACE: u32 = 1 << 1;
+// Config::flags: add the children forked by target processes to TARGET_PIDS
+pub const FLAG_FOLLOW_FORKS: u32 = 1 << 2;
+// Config::flags: also treat every task whose cgroup is in TARGET_CGROUPS as a target
+pub const FLAG_CGROUP: u32 = 1 << 3;
+// Config::flags: treat every task as a target, except for idle and Config::exclude_pid
+pub const FLAG_ALL: u32 = 1 << 4;
+// Config::flags: capture the stacks of the syscalls and softirqs of at least Config::slow_ns
+pub const FLAG_STACKS: u32 = 1 << 5;
+
+// Indices of the per-CPU RINGBUF_COUNTERS array: events reserved in RING_BUF and events dropped
+// because it was full
+pub const RINGBUF_RESERVED: u32 = 0;
+pub const RINGBUF_DROPPED: u32 = 1;
+
+// Runtime configuration written by userspace into the single-entry CONFIG map before attaching
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    pub offsets: FieldOffsets,
+    pub flags: u32,
+    pub exclude_pid: u32, // Never a target with FLAG_ALL (kernel-delay itself)
+    pub hist_step_ns: u64, // Width of the linear histogram slots, 0 for log2 slots
+    pub slow_ns: u64, // Also stream syscalls, softirqs and run queue waits this long, 0 for none
+}
+
+// Key of the in-kernel STATS aggregation map
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StatKey {
+    pub pid: u32,
+    pub tid: u32,
+    pub kind: u32, // EventType
+    pub id: u32,   // Syscall number, IRQ line or softirq vector, 0 for thread run/ready
+}
+
+// Syscall return values in [-MAX_ERRNO, -1] are errors
+pub const MAX_ERRNO: i64 = 4095;
+
+// StatKey::id of a SyscallErrorStats entry: the errno in the upper 16 bits, the syscall number in
+// the lower ones
+#[inline(always)]
+pub fn syscall_error_id(number: u32, errno: u32) -> u32 {
+    (errno << 16) | (number & 0xFFFF)
+}
+
+// Value of the in-kernel STATS aggregation map (one per CPU)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatValue {
+    pub count: u64,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+}
+
+// Key of the in-kernel HIST latency histogram map (one counter per non-empty slot)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HistKey {
+    pub key: StatKey,
+    pub slot: u32,
+    pub _pad: u32,
+}
+
+// Number of latency histogram slots. With log2 slots the last one starts at 2^62 ns, with
+// linear slots it collects everything beyond the covered range.
+pub const HIST_SLOTS: usize = 64;
+
+// Histogram slot of a latency. Log2 slots like bcc's: slot 0 holds 0, slot n >= 1 holds
+// [2^(n-1), 2^n - 1]. Linear slots: slot n holds [n * step, (n + 1) * step - 1].
+#[inline(always)]
+pub fn hist_slot(latency_ns: u64, step_ns: u64) -> usize {
+    let slot = latency_ns
+        .checked_div(step_ns)
+        .unwrap_or_else(|| log2_slot(latency_ns));
+    if slot >= HIST_SLOTS as u64 {
+        HIST_SLOTS - 1
+    } else {
+        slot as usize
+    }
+}
+
+// Range of latencies (inclusive) that end up in a histogram slot
+pub fn hist_slot_range(slot: usize, step_ns: u64) -> (u64, u64) {
+    if step_ns > 0 {
+        let low = slot as u64 * step_ns;
+        if slot == HIST_SLOTS - 1 {
+            (low, u64::MAX)
+        } else {
+            (low, low + step_ns - 1)
+        }
+    } else if slot == 0 {
+        (0, 0)
+    } else if slot == HIST_SLOTS - 1 {
+        (1 << (slot - 1), u64::MAX)
+    } else {
+        (1 << (slot - 1), (1 << slot) - 1)
+    }
+}
+
+// floor(log2(value)) + 1, written with plain shifts so the verifier is happy with it
+#[inline(always)]
+fn log2_slot(mut value: u64) -> u64 {
+    if value == 0 {
+        return 0;
+    }
+    let mut slot = 1;
+    if value >= 1 << 32 {
+        value >>= 32;
+        slot += 32;
+    }
+    if value >= 1 << 16 {
+        value >>= 16;
+        slot += 16;
+    }
+    if value >= 1 << 8 {
+        value >>= 8;
+        slot += 8;
+    }
+    if value >= 1 << 4 {
+        value >>= 4;
+        slot += 4;
+    }
+    if value >= 1 << 2 {
+        value >>= 2;
+        slot += 2;
+    }
+    if value >= 1 << 1 {
+        slot += 1;
+    }
+    slot
 }
 
 // When compiling for userspace, we need to implement serialization
@@ -86,4 +257,59 @@ unsafe impl aya::Pod for ThreadRunStat {}
 unsafe impl aya::Pod for ThreadReadyStat {}
 
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for IrqStat {}
\ No newline at end of file
+unsafe impl aya::Pod for IrqStat {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FieldOffsets {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for Config {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for StatKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for StatValue {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for HistKey {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log2_slots() {
+        assert_eq!(hist_slot(0, 0), 0);
+        assert_eq!(hist_slot(1, 0), 1);
+        assert_eq!(hist_slot(2, 0), 2);
+        assert_eq!(hist_slot(3, 0), 2);
+        assert_eq!(hist_slot(4, 0), 3);
+        assert_eq!(hist_slot(1023, 0), 10);
+        assert_eq!(hist_slot(1024, 0), 11);
+        assert_eq!(hist_slot(1 << 62, 0), HIST_SLOTS - 1);
+        assert_eq!(hist_slot(u64::MAX, 0), HIST_SLOTS - 1);
+    }
+
+    #[test]
+    fn linear_slots() {
+        assert_eq!(hist_slot(0, 1000), 0);
+        assert_eq!(hist_slot(999, 1000), 0);
+        assert_eq!(hist_slot(1000, 1000), 1);
+        assert_eq!(hist_slot(62_999, 1000), 62);
+        assert_eq!(hist_slot(63_000, 1000), HIST_SLOTS - 1);
+        assert_eq!(hist_slot(u64::MAX, 1000), HIST_SLOTS - 1);
+    }
+
+    #[test]
+    fn slot_ranges_match_slots() {
+        for step_ns in [0, 1, 1000] {
+            for slot in 0..HIST_SLOTS {
+                let (low, high) = hist_slot_range(slot, step_ns);
+                assert_eq!(hist_slot(low, step_ns), slot);
+                assert_eq!(hist_slot(high, step_ns), slot);
+            }
+            assert_eq!(hist_slot_range(HIST_SLOTS - 1, step_ns).1, u64::MAX);
+        }
+    }
+}
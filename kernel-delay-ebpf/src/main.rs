@@ -2,30 +2,245 @@
 #![no_main]
 
 use aya_ebpf::{
-    helpers::{bpf_get_current_pid_tgid, bpf_ktime_get_ns},
+    EbpfContext,
+    bindings::{BPF_F_NO_PREALLOC, BPF_F_USER_STACK},
+    helpers::{
+        bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_ktime_get_ns,
+        bpf_probe_read_kernel_str_bytes,
+        generated::bpf_get_current_cgroup_id,
+    },
     macros::{map, tracepoint},
-    maps::{HashMap, RingBuf},
+    maps::{
+        Array, HashMap, LruHashMap, PerCpuArray, PerCpuHashMap, RingBuf, StackTrace,
+        ring_buf::RingBufEntry,
+    },
     programs::TracePointContext,
 };
 use aya_log_ebpf::debug;
-use kernel_delay_common::{Event, EventType, IrqStat, SyscallStat, ThreadReadyStat, ThreadRunStat};
+use kernel_delay_common::{
+    Config, Event, EventType, FLAG_AGGREGATE, FLAG_ALL, FLAG_CGROUP, FLAG_FOLLOW_FORKS, FLAG_STACKS,
+    FLAG_TRACE,
+    HistKey, IrqStat, MAX_ERRNO, RINGBUF_DROPPED, RINGBUF_RESERVED, StatKey, StatValue, SyscallStat,
+    ThreadReadyStat, ThreadRunStat, hist_slot, syscall_error_id,
+};
 
 // Ring buffer for sending events to userspace
 #[map]
 static RING_BUF: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
 
-// Map to store the target PID
+// Events reserved in RING_BUF and events dropped because it was full, per CPU (indexed by
+// RINGBUF_RESERVED and RINGBUF_DROPPED)
+#[map]
+static RINGBUF_COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(2, 0);
+
+// Runtime configuration (tracepoint field offsets etc.), written by userspace at startup
+#[map]
+static CONFIG: Array<Config> = Array::with_max_entries(1, 0);
+
+// In-kernel aggregation of every measurement, read by userspace at the end of the run. Entries
+// are only allocated when used, a preallocated per-CPU map would cost max_entries * nr_cpus.
+#[map]
+static STATS: PerCpuHashMap<StatKey, StatValue> =
+    PerCpuHashMap::with_max_entries(65536, BPF_F_NO_PREALLOC);
+
+// Latency histograms of the STATS entries, one counter per non-empty slot
+#[map]
+static HIST: PerCpuHashMap<HistKey, u64> =
+    PerCpuHashMap::with_max_entries(262144, BPF_F_NO_PREALLOC);
+
+// Set of target processes (PID -> 1), seeded by userspace and, when following forks, extended
+// with the children of target processes. Userspace removes the processes that exited.
+#[map]
+static TARGET_PIDS: HashMap<u32, u8> = HashMap::with_max_entries(8192, 0);
+
+// Children of target processes that could not be followed because TARGET_PIDS was full, per CPU
 #[map]
-static TARGET_PID: HashMap<u64, u64> = HashMap::with_max_entries(1, 0);
+static FORKS_MISSED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
 
 // Maps to track syscall timing
 #[map]
-static SYSCALL_START_TIME: HashMap<u64, u64> = HashMap::with_max_entries(10240, 0);
+static SYSCALL_START_TIME: HashMap<u64, u64> = HashMap::with_max_entries(65536, 0);
 
 // Maps to track softirq timing
 #[map]
 static SOFTIRQ_START_TIME: HashMap<u64, u64> = HashMap::with_max_entries(10240, 0);
 
+// Maps to track hard IRQ handler timing
+#[map]
+static HARDIRQ_START: HashMap<u64, HardIrqStart> = HashMap::with_max_entries(10240, 0);
+
+// Names of the hard IRQ handlers seen so far (IRQ line -> name)
+#[map]
+static HARDIRQ_NAMES: HashMap<u32, [u8; 16]> = HashMap::with_max_entries(1024, 0);
+
+// Hard IRQ handler in progress on top of a target thread
+#[derive(Clone, Copy)]
+struct HardIrqStart {
+    start_time: u64,
+    irq: u32,
+    name: [u8; 16],
+}
+
+// cgroup v2 ids (the inode numbers of the cgroup directories) of the target cgroups and all their
+// descendants, maintained by userspace
+#[map]
+static TARGET_CGROUPS: HashMap<u64, u8> = HashMap::with_max_entries(4096, 0);
+
+// Threads (TID -> PID) of the target process. sched_switch only tells us the TID of the task
+// being switched in, so this is seeded by userspace, filled in when a target creates a thread or
+// a followed process and whenever a target thread runs.
+// Sized for system-wide tracing, like the other per-thread maps.
+#[map]
+static TARGET_TIDS: HashMap<u32, u32> = HashMap::with_max_entries(65536, 0);
+
+// Names (comm) of the target threads (TID -> comm), set when a target creates the thread or else
+// captured the first time it is measured, and updated on task_rename. Kept after the thread exits so that it can still be reported, the
+// least recently used entries make room for new threads.
+#[map]
+static THREAD_NAMES: LruHashMap<u32, [u8; 16]> = LruHashMap::with_max_entries(65536, 0);
+
+// Kernel and user stacks of the slow events (with FLAG_STACKS), symbolized by userspace. Stacks
+// that collide with another one in the same bucket are not captured.
+#[map]
+static STACKS: StackTrace = StackTrace::with_max_entries(16384, 0);
+
+// Maps to track when target threads were switched in
+#[map]
+static ONCPU_START_TIME: HashMap<u32, u64> = HashMap::with_max_entries(65536, 0);
+
+// Maps to track when target threads became runnable (woken up or preempted)
+#[map]
+static WAKEUP_TIME: HashMap<u32, u64> = HashMap::with_max_entries(65536, 0);
+
+// Bits of sched_switch `prev_state` that mean the task went to sleep. With none of them set the
+// task was preempted (or yielded) and goes straight back to the run queue.
+const TASK_REPORT_MASK: u32 = 0xff;
+
+// task_newtask `clone_flags` bit of a new thread, sharing the thread group of its creator
+const CLONE_THREAD: u64 = 0x10000;
+
+fn config() -> Result<&'static Config, i64> {
+    CONFIG.get(0).ok_or(1i64)
+}
+
+// Whether the current task is monitored, `pid` being its PID. Besides the PID set, a task also
+// matches when it lives in one of the target cgroups, or always when tracing the whole system.
+fn is_target(pid: u32) -> bool {
+    unsafe {
+        if TARGET_PIDS.get(&pid).is_some() {
+            return true;
+        }
+        let Ok(config) = config() else {
+            return false;
+        };
+        if config.flags & FLAG_ALL != 0 {
+            // PID 0 are the per-CPU idle tasks
+            return pid != 0 && pid != config.exclude_pid;
+        }
+        config.flags & FLAG_CGROUP != 0
+            && TARGET_CGROUPS.get(&bpf_get_current_cgroup_id()).is_some()
+    }
+}
+
+// Name of a target thread. The first time it is asked for, the name can only be captured while
+// the thread is current (zeroes otherwise), later renames are picked up by task_rename.
+unsafe fn thread_comm(tid: u32) -> [u8; 16] {
+    if let Some(name) = THREAD_NAMES.get(&tid) {
+        return *name;
+    }
+    if bpf_get_current_pid_tgid() as u32 != tid {
+        return [0; 16];
+    }
+    let name = bpf_get_current_comm().unwrap_or_default();
+    let _ = THREAD_NAMES.insert(&tid, &name, 0);
+    name
+}
+
+// Accumulate a measurement into STATS when aggregating. Returns whether the raw event should also
+// be streamed to userspace through RING_BUF: always when tracing, else only the slow ones.
+unsafe fn record_stat(
+    pid: u32,
+    tid: u32,
+    kind: EventType,
+    id: u32,
+    latency: u64,
+) -> Result<bool, i64> {
+    let config = config()?;
+    let flags = config.flags;
+    // Userspace names the threads of STATS from THREAD_NAMES
+    thread_comm(tid);
+    if flags & FLAG_AGGREGATE != 0 {
+        let key = StatKey {
+            pid,
+            tid,
+            kind: kind as u32,
+            id,
+        };
+        // The map is per-CPU, so the value can be updated in place without atomics
+        match STATS.get_ptr_mut(&key) {
+            Some(value) => {
+                (*value).count += 1;
+                (*value).total_ns += latency;
+                if latency < (*value).min_ns {
+                    (*value).min_ns = latency;
+                }
+                if latency > (*value).max_ns {
+                    (*value).max_ns = latency;
+                }
+            }
+            None => {
+                let value = StatValue {
+                    count: 1,
+                    total_ns: latency,
+                    min_ns: latency,
+                    max_ns: latency,
+                };
+                STATS.insert(&key, &value, 0)?;
+            }
+        }
+
+        let hist_key = HistKey {
+            key,
+            slot: hist_slot(latency, config.hist_step_ns) as u32,
+            _pad: 0,
+        };
+        match HIST.get_ptr_mut(&hist_key) {
+            Some(count) => *count += 1,
+            None => HIST.insert(&hist_key, &1, 0)?,
+        }
+    }
+    let slow = config.slow_ns > 0
+        && latency >= config.slow_ns
+        && matches!(
+            kind,
+            EventType::SyscallStats | EventType::SoftIrqStats | EventType::ThreadReadyStats
+        );
+    Ok(flags & FLAG_TRACE != 0 || slow)
+}
+
+// Ids in STACKS of the kernel and user stacks of the current task, when capturing the stacks of
+// slow events and this one is. -1 otherwise or when the stack could not be stored. Without
+// `kernel`, only the user stack is captured.
+unsafe fn capture_stacks(
+    ctx: &TracePointContext,
+    latency: u64,
+    kernel: bool,
+) -> Result<(i32, i32), i64> {
+    let config = config()?;
+    if config.flags & FLAG_STACKS == 0 || latency < config.slow_ns {
+        return Ok((-1, -1));
+    }
+    let kernel = if kernel {
+        STACKS.get_stackid(ctx, 0).map_or(-1, |id| id as i32)
+    } else {
+        -1
+    };
+    let user = STACKS
+        .get_stackid(ctx, BPF_F_USER_STACK as u64)
+        .map_or(-1, |id| id as i32);
+    Ok((kernel, user))
+}
+
 // Separate tracepoint handlers for different tracepoint types
 #[tracepoint]
 pub fn syscall_enter(ctx: TracePointContext) -> u32 {
@@ -43,6 +258,22 @@ pub fn syscall_exit(ctx: TracePointContext) -> u32 {
     }
 }
 
+#[tracepoint]
+pub fn raw_syscall_enter(ctx: TracePointContext) -> u32 {
+    match unsafe { try_raw_syscall_enter(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn raw_syscall_exit(ctx: TracePointContext) -> u32 {
+    match unsafe { try_raw_syscall_exit(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
 #[tracepoint]
 pub fn softirq_entry(ctx: TracePointContext) -> u32 {
     match unsafe { try_softirq_entry(ctx) } {
@@ -59,58 +290,141 @@ pub fn softirq_exit(ctx: TracePointContext) -> u32 {
     }
 }
 
-unsafe fn try_syscall_enter(ctx: TracePointContext) -> Result<u32, i64> {
+#[tracepoint]
+pub fn irq_handler_entry(ctx: TracePointContext) -> u32 {
+    match unsafe { try_irq_handler_entry(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn irq_handler_exit(ctx: TracePointContext) -> u32 {
+    match unsafe { try_irq_handler_exit(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn sched_switch(ctx: TracePointContext) -> u32 {
+    match unsafe { try_sched_switch(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn sched_wakeup(ctx: TracePointContext) -> u32 {
+    match unsafe { try_sched_wakeup(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn task_newtask(ctx: TracePointContext) -> u32 {
+    match unsafe { try_task_newtask(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn sched_process_exit(ctx: TracePointContext) -> u32 {
+    match unsafe { try_sched_process_exit(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+#[tracepoint]
+pub fn task_rename(ctx: TracePointContext) -> u32 {
+    match unsafe { try_task_rename(ctx) } {
+        Ok(ret) => ret,
+        Err(ret) => ret as u32,
+    }
+}
+
+unsafe fn try_syscall_enter(_ctx: TracePointContext) -> Result<u32, i64> {
+    // The syscall number is only needed on exit
+    syscall_start()
+}
+
+unsafe fn try_syscall_exit(ctx: TracePointContext) -> Result<u32, i64> {
+    // Per-syscall tracepoints carry the number as `int __syscall_nr`
+    let offsets = &config()?.offsets;
+    let syscall_id = ctx.read_at::<i32>(offsets.syscall_nr as usize)?;
+    let ret = ctx.read_at::<i64>(offsets.syscall_ret as usize)?;
+    syscall_end(&ctx, syscall_id as u32, ret)
+}
+
+unsafe fn try_raw_syscall_enter(_ctx: TracePointContext) -> Result<u32, i64> {
+    syscall_start()
+}
+
+unsafe fn try_raw_syscall_exit(ctx: TracePointContext) -> Result<u32, i64> {
+    // raw_syscalls carry the number as `long id`, which is -1 when the syscall was skipped
+    // (e.g. by seccomp or ptrace)
+    let offsets = &config()?.offsets;
+    let syscall_id = ctx.read_at::<i64>(offsets.raw_syscall_id as usize)?;
+    if syscall_id < 0 {
+        let _ = SYSCALL_START_TIME.remove(&bpf_get_current_pid_tgid());
+        return Ok(0);
+    }
+    let ret = ctx.read_at::<i64>(offsets.raw_syscall_ret as usize)?;
+    syscall_end(&ctx, syscall_id as u32, ret)
+}
+
+unsafe fn syscall_start() -> Result<u32, i64> {
     // Get current process PID and TID
     let pid_tgid = bpf_get_current_pid_tgid();
     let pid = (pid_tgid >> 32) as u32;
     let tid = (pid_tgid & 0xFFFFFFFF) as u32;
     
-    // Get target PID from the map (stored as a single entry with key 0)
-    let target_pid_ptr = TARGET_PID.get(&0u64).ok_or(1i64)?;
-    let target_pid = *target_pid_ptr as u32;
-    
-    // If current process is not the target PID, skip
-    if pid != target_pid {
+    // If current process is not a target, skip
+    if !is_target(pid) {
         return Ok(0);
     }
     
-    // Extract syscall ID
-    if let Ok(_syscall_id) = ctx.read_at::<u64>(16) {
-        let current_time = bpf_ktime_get_ns();
-        let key = ((pid as u64) << 32) | (tid as u64);
-        SYSCALL_START_TIME.insert(&key, &current_time, 0)?;
-    }
+    let current_time = bpf_ktime_get_ns();
+    let key = ((pid as u64) << 32) | (tid as u64);
+    SYSCALL_START_TIME.insert(&key, &current_time, 0)?;
     
     Ok(0)
 }
 
-unsafe fn try_syscall_exit(ctx: TracePointContext) -> Result<u32, i64> {
+unsafe fn syscall_end(ctx: &TracePointContext, syscall_id: u32, ret: i64) -> Result<u32, i64> {
     // Get current process PID and TID
     let pid_tgid = bpf_get_current_pid_tgid();
     let pid = (pid_tgid >> 32) as u32;
     let tid = (pid_tgid & 0xFFFFFFFF) as u32;
     
-    // Get target PID from the map (stored as a single entry with key 0)
-    let target_pid_ptr = TARGET_PID.get(&0u64).ok_or(1i64)?;
-    let target_pid = *target_pid_ptr as u32;
-    
-    // If current process is not the target PID, skip
-    if pid != target_pid {
+    // If current process is not a target, skip
+    if !is_target(pid) {
         return Ok(0);
     }
     
-    // Extract syscall ID
-    if let Ok(syscall_id) = ctx.read_at::<u64>(16) {
-        let current_time = bpf_ktime_get_ns();
-        let key = ((pid as u64) << 32) | (tid as u64);
-        if let Some(start_time) = SYSCALL_START_TIME.get(&key) {
-            let latency = current_time - *start_time;
-            
-            // Remove the start time entry
-            SYSCALL_START_TIME.remove(&key)?;
-            
-            // Create and send syscall event
-            send_syscall_event(pid, tid, syscall_id as u32, latency, current_time)?;
+    let current_time = bpf_ktime_get_ns();
+    let key = ((pid as u64) << 32) | (tid as u64);
+    if let Some(start_time) = SYSCALL_START_TIME.get(&key) {
+        let latency = current_time - *start_time;
+        
+        // Remove the start time entry
+        SYSCALL_START_TIME.remove(&key)?;
+        
+        // Aggregate and/or send syscall event
+        if record_stat(pid, tid, EventType::SyscallStats, syscall_id, latency)? {
+            // The user stack is where the syscall was made from. Its kernel stack is only the
+            // return path by now, where it blocked is long gone.
+            let stacks = capture_stacks(ctx, latency, false)?;
+            send_syscall_event(pid, tid, syscall_id, ret, latency, current_time, stacks)?;
+        }
+
+        // Failures are also counted per errno, the streamed event carries the return value
+        if (-MAX_ERRNO..0).contains(&ret) {
+            let id = syscall_error_id(syscall_id, (-ret) as u32);
+            record_stat(pid, tid, EventType::SyscallErrorStats, id, latency)?;
         }
     }
     
@@ -123,17 +437,13 @@ unsafe fn try_softirq_entry(ctx: TracePointContext) -> Result<u32, i64> {
     let pid = (pid_tgid >> 32) as u32;
     let tid = (pid_tgid & 0xFFFFFFFF) as u32;
     
-    // Get target PID from the map (stored as a single entry with key 0)
-    let target_pid_ptr = TARGET_PID.get(&0u64).ok_or(1i64)?;
-    let target_pid = *target_pid_ptr as u32;
-    
-    // If current process is not the target PID, skip
-    if pid != target_pid {
+    // If current process is not a target, skip
+    if !is_target(pid) {
         return Ok(0);
     }
     
     // Extract vector (softirq type)
-    if let Ok(vector) = ctx.read_at::<u32>(8) {
+    if let Ok(vector) = ctx.read_at::<u32>(config()?.offsets.softirq_vec as usize) {
         let current_time = bpf_ktime_get_ns();
         let key = ((pid as u64) << 32) | ((tid as u64) << 16) | (vector as u64);
         SOFTIRQ_START_TIME.insert(&key, &current_time, 0)?;
@@ -148,17 +458,13 @@ unsafe fn try_softirq_exit(ctx: TracePointContext) -> Result<u32, i64> {
     let pid = (pid_tgid >> 32) as u32;
     let tid = (pid_tgid & 0xFFFFFFFF) as u32;
     
-    // Get target PID from the map (stored as a single entry with key 0)
-    let target_pid_ptr = TARGET_PID.get(&0u64).ok_or(1i64)?;
-    let target_pid = *target_pid_ptr as u32;
-    
-    // If current process is not the target PID, skip
-    if pid != target_pid {
+    // If current process is not a target, skip
+    if !is_target(pid) {
         return Ok(0);
     }
     
     // Extract vector (softirq type)
-    if let Ok(vector) = ctx.read_at::<u32>(8) {
+    if let Ok(vector) = ctx.read_at::<u32>(config()?.offsets.softirq_vec as usize) {
         let current_time = bpf_ktime_get_ns();
         let key = ((pid as u64) << 32) | ((tid as u64) << 16) | (vector as u64);
         if let Some(start_time) = SOFTIRQ_START_TIME.get(&key) {
@@ -167,40 +473,231 @@ unsafe fn try_softirq_exit(ctx: TracePointContext) -> Result<u32, i64> {
             // Remove the start time entry
             SOFTIRQ_START_TIME.remove(&key)?;
             
-            // Create and send softirq event
-            send_softirq_event(pid, tid, vector, latency, current_time)?;
+            // Aggregate and/or send softirq event
+            if record_stat(pid, tid, EventType::SoftIrqStats, vector, latency)? {
+                // What the softirq ran on top of
+                let stacks = capture_stacks(&ctx, latency, true)?;
+                send_softirq_event(pid, tid, vector, latency, current_time, stacks)?;
+            }
         }
     }
     
     Ok(0)
 }
 
-fn send_syscall_event(_pid: u32, tid: u32, syscall_id: u32, latency: u64, timestamp: u64) -> Result<u32, i64> {
-    // Reserve space in the ring buffer for our event
-    if let Some(mut entry) = RING_BUF.reserve::<Event>(0) {
-        // Create syscall name based on ID
-        let mut name = [0u8; 16];
-        let syscall_name = get_syscall_name(syscall_id);
-        
-        // Copy the syscall name
-        for i in 0..syscall_name.len().min(name.len()) {
-            name[i] = syscall_name[i];
+unsafe fn try_irq_handler_entry(ctx: TracePointContext) -> Result<u32, i64> {
+    // Get current (interrupted) process PID and TID
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32;
+
+    // If the interrupted process is not a target, skip
+    if !is_target(pid) {
+        return Ok(0);
+    }
+
+    let offsets = &config()?.offsets;
+    let irq = ctx.read_at::<i32>(offsets.irq_handler_irq as usize)? as u32;
+
+    // `name` is a __data_loc field: the low 16 bits hold the offset of the string in the record
+    let mut name = [0u8; 16];
+    let data_loc = ctx.read_at::<u32>(offsets.irq_handler_name as usize)?;
+    let name_ptr = (ctx.as_ptr() as *const u8).add((data_loc & 0xFFFF) as usize);
+    let _ = bpf_probe_read_kernel_str_bytes(name_ptr, &mut name);
+
+    let start = HardIrqStart {
+        start_time: bpf_ktime_get_ns(),
+        irq,
+        name,
+    };
+    HARDIRQ_START.insert(&pid_tgid, &start, 0)?;
+
+    Ok(0)
+}
+
+unsafe fn try_irq_handler_exit(ctx: TracePointContext) -> Result<u32, i64> {
+    // Get current (interrupted) process PID and TID
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32;
+    let tid = (pid_tgid & 0xFFFFFFFF) as u32;
+
+    // Only handlers whose entry was recorded for a target thread are of interest
+    if let Some(start) = HARDIRQ_START.get(&pid_tgid) {
+        let start = *start;
+        HARDIRQ_START.remove(&pid_tgid)?;
+
+        let irq = ctx.read_at::<i32>(config()?.offsets.irq_handler_irq as usize)? as u32;
+        if irq == start.irq {
+            let current_time = bpf_ktime_get_ns();
+            let latency = current_time - start.start_time;
+            HARDIRQ_NAMES.insert(&irq, &start.name, 0)?;
+            if record_stat(pid, tid, EventType::HardIrqStats, irq, latency)? {
+                send_hardirq_event(pid, tid, irq, &start.name, latency, current_time)?;
+            }
         }
-        
+    }
+
+    Ok(0)
+}
+
+unsafe fn try_sched_switch(ctx: TracePointContext) -> Result<u32, i64> {
+    let offsets = &config()?.offsets;
+    let prev_tid = ctx.read_at::<i32>(offsets.sched_prev_pid as usize)? as u32;
+    let next_tid = ctx.read_at::<i32>(offsets.sched_next_pid as usize)? as u32;
+    let current_time = bpf_ktime_get_ns();
+
+    // The task being switched out is still current, so its PID is known
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    if is_target(pid) {
+        TARGET_TIDS.insert(&prev_tid, &pid, 0)?;
+
+        // Close the on-CPU slice that started when the thread was switched in
+        if let Some(start_time) = ONCPU_START_TIME.get(&prev_tid) {
+            let run_time = current_time - *start_time;
+            ONCPU_START_TIME.remove(&prev_tid)?;
+            if record_stat(pid, prev_tid, EventType::ThreadRunStats, 0, run_time)? {
+                send_thread_run_event(pid, prev_tid, run_time, current_time)?;
+            }
+        }
+
+        // An involuntarily switched out thread starts waiting on the run queue right away
+        let prev_state = ctx.read_at::<u32>(offsets.sched_prev_state as usize)?;
+        if prev_state & TASK_REPORT_MASK == 0 {
+            WAKEUP_TIME.insert(&prev_tid, &current_time, 0)?;
+        }
+    }
+
+    // The task being switched in is only known by its TID
+    if let Some(&next_pid) = TARGET_TIDS.get(&next_tid) {
+        ONCPU_START_TIME.insert(&next_tid, &current_time, 0)?;
+
+        // Close the run queue wait that started when the thread became runnable
+        if let Some(wakeup_time) = WAKEUP_TIME.get(&next_tid) {
+            let ready_time = current_time - *wakeup_time;
+            WAKEUP_TIME.remove(&next_tid)?;
+            if record_stat(next_pid, next_tid, EventType::ThreadReadyStats, 0, ready_time)? {
+                send_thread_ready_event(next_pid, next_tid, ready_time, current_time)?;
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+unsafe fn try_sched_wakeup(ctx: TracePointContext) -> Result<u32, i64> {
+    // The woken up task is not current, so it can only be matched by TID
+    let tid = ctx.read_at::<i32>(config()?.offsets.sched_wakeup_pid as usize)? as u32;
+    if TARGET_TIDS.get(&tid).is_some() {
+        let current_time = bpf_ktime_get_ns();
+        WAKEUP_TIME.insert(&tid, &current_time, 0)?;
+    }
+
+    Ok(0)
+}
+
+unsafe fn try_task_newtask(ctx: TracePointContext) -> Result<u32, i64> {
+    // The creator is current and the new task has not been woken up yet. Adding it to TARGET_TIDS
+    // now lets sched_wakeup_new start its first run queue wait.
+    let config = config()?;
+    let child = ctx.read_at::<i32>(config.offsets.newtask_pid as usize)? as u32;
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    if !is_target(pid) {
+        return Ok(0);
+    }
+
+    // Inherited from the creator, until the child renames itself or execs. Overwrites the name
+    // of a dead thread that had the same TID.
+    let comm = ctx.read_at::<[u8; 16]>(config.offsets.newtask_comm as usize)?;
+    let _ = THREAD_NAMES.insert(&child, &comm, 0);
+
+    let clone_flags = ctx.read_at::<u64>(config.offsets.newtask_clone_flags as usize)?;
+    if clone_flags & CLONE_THREAD != 0 {
+        // Without room the thread is still added by sched_switch, only its first wait is missed
+        let _ = TARGET_TIDS.insert(&child, &pid, 0);
+    } else if config.flags & FLAG_FOLLOW_FORKS != 0 {
+        // A new process, whose PID is the TID of its main thread
+        if TARGET_PIDS.insert(&child, &1, 0).is_err() {
+            if let Some(counter) = FORKS_MISSED.get_ptr_mut(0) {
+                *counter += 1;
+            }
+            return Ok(0);
+        }
+        let _ = TARGET_TIDS.insert(&child, &child, 0);
+    }
+
+    Ok(0)
+}
+
+unsafe fn try_sched_process_exit(ctx: TracePointContext) -> Result<u32, i64> {
+    // Fires for every exiting thread, `pid` is its TID
+    let tid = ctx.read_at::<i32>(config()?.offsets.exit_pid as usize)? as u32;
+    if TARGET_TIDS.get(&tid).is_some() {
+        TARGET_TIDS.remove(&tid)?;
+        let _ = ONCPU_START_TIME.remove(&tid);
+        let _ = WAKEUP_TIME.remove(&tid);
+    }
+
+    // exit and exit_group never return, so their syscall never reaches sys_exit. The exiting
+    // task is current.
+    let _ = SYSCALL_START_TIME.remove(&bpf_get_current_pid_tgid());
+
+    // TARGET_PIDS is left alone: the main thread may exit (pthread_exit) before the others, and
+    // whether it is the last one isn't known here. Userspace drops processes that are gone.
+    Ok(0)
+}
+
+unsafe fn try_task_rename(ctx: TracePointContext) -> Result<u32, i64> {
+    // A thread can only be renamed by itself or another thread of its process (prctl, exec or
+    // /proc/<pid>/task/<tid>/comm), so the current PID tells whether it is a target
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32;
+    if !is_target(pid) {
+        return Ok(0);
+    }
+
+    // Recent kernels dropped the `pid` field, they only rename current
+    let offsets = &config()?.offsets;
+    let tid = if offsets.task_rename_pid != 0 {
+        ctx.read_at::<i32>(offsets.task_rename_pid as usize)? as u32
+    } else {
+        pid_tgid as u32
+    };
+    let name = ctx.read_at::<[u8; 16]>(offsets.task_rename_newcomm as usize)?;
+    THREAD_NAMES.insert(&tid, &name, 0)?;
+
+    Ok(0)
+}
+
+// Reserve an event in the ring buffer, counting the reservations and the events dropped because
+// userspace doesn't keep up
+#[inline(always)]
+fn reserve_event() -> Option<RingBufEntry<Event>> {
+    let entry = RING_BUF.reserve::<Event>(0);
+    let index = if entry.is_some() {
+        RINGBUF_RESERVED
+    } else {
+        RINGBUF_DROPPED
+    };
+    if let Some(counter) = RINGBUF_COUNTERS.get_ptr_mut(index) {
+        unsafe { *counter += 1 };
+    }
+    entry
+}
+
+fn send_syscall_event(pid: u32, tid: u32, syscall_id: u32, ret: i64, latency: u64, timestamp: u64, stacks: (i32, i32)) -> Result<u32, i64> {
+    // Reserve space in the ring buffer for our event
+    if let Some(mut entry) = reserve_event() {
+        // Userspace names the syscall from its number, with the table of the architecture
         let syscall_stat = SyscallStat {
-            name,
+            name: [0; 16],
             number: syscall_id,
             count: 1,
             total_ns: latency,
             max_ns: latency,
+            ret,
         };
         
-        // Create a simple thread name using the TID
-        let mut thread_name = [0u8; 16];
-        let simple_name = b"thread";
-        for i in 0..simple_name.len().min(thread_name.len()) {
-            thread_name[i] = simple_name[i];
-        }
+        // The thread's comm, as last seen by the kernel
+        let thread_name = unsafe { thread_comm(tid) };
         
         let mut resource_type = [0u8; 32];
         resource_type[0] = b'[';
@@ -227,6 +724,7 @@ fn send_syscall_event(_pid: u32, tid: u32, syscall_id: u32, latency: u64, timest
         let event = Event {
             timestamp,
             tid,
+            pid,
             thread_name,
             resource_type,
             event_type: EventType::SyscallStats as u32,
@@ -250,6 +748,8 @@ fn send_syscall_event(_pid: u32, tid: u32, syscall_id: u32, latency: u64, timest
                 vector: 0,
             },
             total_excluding_poll: latency,
+            kernel_stack_id: stacks.0,
+            user_stack_id: stacks.1,
         };
         
         // Write the event to the ring buffer entry
@@ -265,9 +765,9 @@ fn send_syscall_event(_pid: u32, tid: u32, syscall_id: u32, latency: u64, timest
     Ok(0)
 }
 
-fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp: u64) -> Result<u32, i64> {
+fn send_softirq_event(pid: u32, tid: u32, vector: u32, latency: u64, timestamp: u64, stacks: (i32, i32)) -> Result<u32, i64> {
     // Reserve space in the ring buffer for our event
-    if let Some(mut entry) = RING_BUF.reserve::<Event>(0) {
+    if let Some(mut entry) = reserve_event() {
         // Create softirq name based on vector
         let mut name = [0u8; 16];
         let irq_name = get_softirq_name(vector);
@@ -285,12 +785,8 @@ fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp:
             vector,
         };
         
-        // Create a simple thread name using the TID
-        let mut thread_name = [0u8; 16];
-        let simple_name = b"thread";
-        for i in 0..simple_name.len().min(thread_name.len()) {
-            thread_name[i] = simple_name[i];
-        }
+        // The thread's comm, as last seen by the kernel
+        let thread_name = unsafe { thread_comm(tid) };
         
         let mut resource_type = [0u8; 32];
         resource_type[0] = b'[';
@@ -318,6 +814,7 @@ fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp:
         let event = Event {
             timestamp,
             tid,
+            pid,
             thread_name,
             resource_type,
             event_type: EventType::SoftIrqStats as u32,
@@ -327,6 +824,7 @@ fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp:
                 count: 0,
                 total_ns: 0,
                 max_ns: 0,
+                ret: 0,
             },
             thread_run_stat: ThreadRunStat {
                 sched_cnt: 0,
@@ -341,6 +839,8 @@ fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp:
             },
             irq_stat,
             total_excluding_poll: 0,
+            kernel_stack_id: stacks.0,
+            user_stack_id: stacks.1,
         };
         
         // Write the event to the ring buffer entry
@@ -356,40 +856,192 @@ fn send_softirq_event(_pid: u32, tid: u32, vector: u32, latency: u64, timestamp:
     Ok(0)
 }
 
-fn get_syscall_name(id: u32) -> &'static [u8] {
-    match id {
-        0 => b"read",
-        1 => b"write",
-        2 => b"open",
-        3 => b"close",
-        4 => b"stat",
-        5 => b"fstat",
-        6 => b"lstat",
-        7 => b"poll",
-        8 => b"lseek",
-        9 => b"mmap",
-        10 => b"mprotect",
-        11 => b"munmap",
-        12 => b"brk",
-        13 => b"rt_sigaction",
-        14 => b"rt_sigprocmask",
-        15 => b"rt_sigreturn",
-        16 => b"ioctl",
-        17 => b"pread64",
-        18 => b"pwrite64",
-        19 => b"readv",
-        20 => b"writev",
-        56 => b"clone",
-        57 => b"fork",
-        58 => b"vfork",
-        59 => b"execve",
-        60 => b"exit",
-        61 => b"wait4",
-        62 => b"kill",
-        158 => b"arch_prctl",
-        231 => b"exit_group",
-        _ => b"unknown",
+fn send_thread_run_event(pid: u32, tid: u32, run_time: u64, timestamp: u64) -> Result<u32, i64> {
+    // Reserve space in the ring buffer for our event
+    if let Some(mut entry) = reserve_event() {
+        // Every event carries a single on-CPU slice, userspace aggregates them
+        let thread_run_stat = ThreadRunStat {
+            sched_cnt: 1,
+            total_ns: run_time,
+            min_ns: run_time,
+            max_ns: run_time,
+        };
+
+        // The thread's comm, as last seen by the kernel
+        let thread_name = unsafe { thread_comm(tid) };
+
+        let mut resource_type = [0u8; 32];
+        let resource_name = b"[THREAD RUN STATISTICS]";
+        for i in 0..resource_name.len().min(resource_type.len()) {
+            resource_type[i] = resource_name[i];
+        }
+
+        let event = Event {
+            timestamp,
+            tid,
+            pid,
+            thread_name,
+            resource_type,
+            event_type: EventType::ThreadRunStats as u32,
+            syscall_stat: SyscallStat {
+                name: [0; 16],
+                number: 0,
+                count: 0,
+                total_ns: 0,
+                max_ns: 0,
+                ret: 0,
+            },
+            thread_run_stat,
+            thread_ready_stat: ThreadReadyStat {
+                sched_cnt: 0,
+                total_ns: 0,
+                max_ns: 0,
+            },
+            irq_stat: IrqStat {
+                name: [0; 16],
+                count: 0,
+                total_ns: 0,
+                max_ns: 0,
+                vector: 0,
+            },
+            total_excluding_poll: 0,
+            kernel_stack_id: -1,
+            user_stack_id: -1,
+        };
+
+        // Write the event to the ring buffer entry
+        entry.write(event);
+
+        // Submit the entry to make it visible to userspace
+        entry.submit(0);
+    }
+
+    Ok(0)
+}
+
+fn send_thread_ready_event(pid: u32, tid: u32, ready_time: u64, timestamp: u64) -> Result<u32, i64> {
+    // Reserve space in the ring buffer for our event
+    if let Some(mut entry) = reserve_event() {
+        // Every event carries a single run queue wait, userspace aggregates them
+        let thread_ready_stat = ThreadReadyStat {
+            sched_cnt: 1,
+            total_ns: ready_time,
+            max_ns: ready_time,
+        };
+
+        // The thread's comm, as last seen by the kernel
+        let thread_name = unsafe { thread_comm(tid) };
+
+        let mut resource_type = [0u8; 32];
+        let resource_name = b"[THREAD READY STATISTICS]";
+        for i in 0..resource_name.len().min(resource_type.len()) {
+            resource_type[i] = resource_name[i];
+        }
+
+        let event = Event {
+            timestamp,
+            tid,
+            pid,
+            thread_name,
+            resource_type,
+            event_type: EventType::ThreadReadyStats as u32,
+            syscall_stat: SyscallStat {
+                name: [0; 16],
+                number: 0,
+                count: 0,
+                total_ns: 0,
+                max_ns: 0,
+                ret: 0,
+            },
+            thread_run_stat: ThreadRunStat {
+                sched_cnt: 0,
+                total_ns: 0,
+                min_ns: 0,
+                max_ns: 0,
+            },
+            thread_ready_stat,
+            irq_stat: IrqStat {
+                name: [0; 16],
+                count: 0,
+                total_ns: 0,
+                max_ns: 0,
+                vector: 0,
+            },
+            total_excluding_poll: 0,
+            kernel_stack_id: -1,
+            user_stack_id: -1,
+        };
+
+        // Write the event to the ring buffer entry
+        entry.write(event);
+
+        // Submit the entry to make it visible to userspace
+        entry.submit(0);
+    }
+
+    Ok(0)
+}
+
+fn send_hardirq_event(pid: u32, tid: u32, irq: u32, name: &[u8; 16], latency: u64, timestamp: u64) -> Result<u32, i64> {
+    // Reserve space in the ring buffer for our event
+    if let Some(mut entry) = reserve_event() {
+        let irq_stat = IrqStat {
+            name: *name,
+            count: 1,
+            total_ns: latency,
+            max_ns: latency,
+            vector: irq,
+        };
+
+        // The thread's comm, as last seen by the kernel
+        let thread_name = unsafe { thread_comm(tid) };
+
+        let mut resource_type = [0u8; 32];
+        let resource_name = b"[HARD IRQ STATISTICS]";
+        for i in 0..resource_name.len().min(resource_type.len()) {
+            resource_type[i] = resource_name[i];
+        }
+
+        let event = Event {
+            timestamp,
+            tid,
+            pid,
+            thread_name,
+            resource_type,
+            event_type: EventType::HardIrqStats as u32,
+            syscall_stat: SyscallStat {
+                name: [0; 16],
+                number: 0,
+                count: 0,
+                total_ns: 0,
+                max_ns: 0,
+                ret: 0,
+            },
+            thread_run_stat: ThreadRunStat {
+                sched_cnt: 0,
+                total_ns: 0,
+                min_ns: 0,
+                max_ns: 0,
+            },
+            thread_ready_stat: ThreadReadyStat {
+                sched_cnt: 0,
+                total_ns: 0,
+                max_ns: 0,
+            },
+            irq_stat,
+            total_excluding_poll: 0,
+            kernel_stack_id: -1,
+            user_stack_id: -1,
+        };
+
+        // Write the event to the ring buffer entry
+        entry.write(event);
+
+        // Submit the entry to make it visible to userspace
+        entry.submit(0);
     }
+
+    Ok(0)
 }
 
 fn get_softirq_name(vector: u32) -> &'static [u8] {
@@ -0,0 +1,215 @@
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, anyhow, bail};
+
+// Mount point of the cgroup v2 (unified) hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Shortest container ID prefix accepted, the length `docker ps` abbreviates them to
+const MIN_CONTAINER_ID_LEN: usize = 12;
+
+// Prefixes of the container cgroups of Docker, Podman, containerd and CRI-O (`<prefix><id>.scope`
+// with the systemd cgroup driver, `<prefix><id>` or just `<id>` below the runtime's own cgroup
+// otherwise)
+const CONTAINER_PREFIXES: [&str; 4] = ["docker-", "libpod-", "cri-containerd-", "crio-"];
+
+// bpf_get_current_cgroup_id() only knows about cgroup v2
+fn check_unified() -> anyhow::Result<()> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        bail!("{CGROUP_ROOT} is not a cgroup v2 hierarchy, cgroup filtering needs cgroup v2");
+    }
+    Ok(())
+}
+
+/// Directory of a cgroup given either as a full path below `/sys/fs/cgroup` or relative to it
+/// (e.g. `system.slice/nginx.service`).
+pub fn from_path(path: &str) -> anyhow::Result<PathBuf> {
+    check_unified()?;
+    let dir = if Path::new(path).starts_with(CGROUP_ROOT) {
+        PathBuf::from(path)
+    } else {
+        Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'))
+    };
+    if !dir.is_dir() {
+        bail!("cgroup {} does not exist", dir.display());
+    }
+    Ok(dir)
+}
+
+/// Directory of a systemd unit's cgroup. A name without a suffix is taken as a service.
+pub fn from_unit(unit: &str) -> anyhow::Result<PathBuf> {
+    check_unified()?;
+    let unit = if unit.contains('.') {
+        unit.to_string()
+    } else {
+        format!("{unit}.service")
+    };
+    find(Path::new(CGROUP_ROOT), &|name| name == unit)
+        .ok_or_else(|| anyhow!("no cgroup found for unit {unit}"))
+}
+
+/// Directory of a container's cgroup. Runtimes name it after the container ID, which may be
+/// abbreviated down to 12 characters, e.g. `docker-<id>.scope`, `libpod-<id>.scope`,
+/// `cri-containerd-<id>.scope` or `docker/<id>`.
+pub fn from_container_id(id: &str) -> anyhow::Result<PathBuf> {
+    if id.len() < MIN_CONTAINER_ID_LEN || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!(
+            "invalid container ID {id}, expected at least {MIN_CONTAINER_ID_LEN} hexadecimal \
+             characters"
+        );
+    }
+    check_unified()?;
+    let id = id.to_ascii_lowercase();
+    let mut dirs = find_all(Path::new(CGROUP_ROOT), &|name| {
+        container_id(name).is_some_and(|full| full.starts_with(&id))
+    });
+    match dirs.len() {
+        0 => bail!("no cgroup found for container {id}"),
+        1 => Ok(dirs.remove(0)),
+        _ => bail!(
+            "container ID {id} is ambiguous, it matches {}",
+            dirs.iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// Full container ID a cgroup directory is named after, if it is one
+fn container_id(name: &str) -> Option<&str> {
+    let name = name.strip_suffix(".scope").unwrap_or(name);
+    let id = CONTAINER_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    (id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))).then_some(id)
+}
+
+// Breadth first, so that the outermost matching cgroup wins
+fn find(root: &Path, matches: &dyn Fn(&str) -> bool) -> Option<PathBuf> {
+    let mut level = vec![root.to_path_buf()];
+    while !level.is_empty() {
+        let mut next = Vec::new();
+        for dir in level {
+            for sub in subdirectories(&dir) {
+                if sub
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(matches)
+                {
+                    return Some(sub);
+                }
+                next.push(sub);
+            }
+        }
+        level = next;
+    }
+    None
+}
+
+// Every matching cgroup, without looking into the matching ones
+fn find_all(root: &Path, matches: &dyn Fn(&str) -> bool) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut level = vec![root.to_path_buf()];
+    while !level.is_empty() {
+        let mut next = Vec::new();
+        for dir in level {
+            for sub in subdirectories(&dir) {
+                if sub
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(matches)
+                {
+                    found.push(sub);
+                } else {
+                    next.push(sub);
+                }
+            }
+        }
+        level = next;
+    }
+    found
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// The cgroup and all its descendants, outermost first.
+pub fn tree(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut i = 0;
+    while i < dirs.len() {
+        let subs = subdirectories(&dirs[i]);
+        dirs.extend(subs);
+        i += 1;
+    }
+    dirs
+}
+
+/// cgroup ID as returned by `bpf_get_current_cgroup_id()`, which is the inode number of the
+/// cgroup directory.
+pub fn id(dir: &Path) -> anyhow::Result<u64> {
+    let metadata =
+        fs::metadata(dir).with_context(|| format!("failed to stat {}", dir.display()))?;
+    Ok(metadata.ino())
+}
+
+/// PIDs of the processes that are members of exactly this cgroup.
+pub fn procs(dir: &Path) -> Vec<u32> {
+    fs::read_to_string(dir.join("cgroup.procs"))
+        .map(|text| text.lines().filter_map(|line| line.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4e5d6c7b8a99887766554433221100ffeeddccbbaa00112233445566778899";
+
+    #[test]
+    fn container_id_of_runtime_cgroups() {
+        for name in [
+            format!("docker-{ID}.scope"),
+            format!("libpod-{ID}.scope"),
+            format!("cri-containerd-{ID}.scope"),
+            format!("crio-{ID}.scope"),
+            format!("libpod-{ID}"),
+            ID.to_string(),
+        ] {
+            assert_eq!(container_id(&name), Some(ID), "{name}");
+        }
+    }
+
+    #[test]
+    fn container_id_of_other_cgroups() {
+        for name in [
+            format!("libpod-conmon-{ID}.scope"),
+            format!("docker-{}.scope", &ID[..63]),
+            format!("{ID}-init"),
+            "docker.service".to_string(),
+            "system.slice".to_string(),
+        ] {
+            assert_eq!(container_id(&name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn container_id_must_be_long_and_hexadecimal() {
+        assert!(from_container_id("3f4e5d").is_err());
+        assert!(from_container_id("3f4e5d6c7b8x").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{collections::HashMap, fs, io::IsTerminal as _, path::Path};
+
+use anyhow::{Context as _, bail};
+use serde::Deserialize;
+
+use crate::{
+    DiffArgs, format_number, procfs,
+    recording::{self, Reader},
+    report::{self, ProcessReport, StatReport},
+    syscalls,
+};
+
+// Process name, thread name, kind (syscall, syscall_error, run, ready, hardirq, softirq) and
+// resource name
+type Key = (String, String, &'static str, String);
+
+// Just what is needed from a JSON report, other fields are ignored
+#[derive(Deserialize)]
+struct JsonReport {
+    version: u32,
+    processes: Vec<ProcessReport>,
+}
+
+// Statistics of the rows sharing a key
+#[derive(Clone, Copy, Debug, Default)]
+struct Merged {
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+    p50_ns: u64,
+    p90_ns: u64,
+    p99_ns: u64,
+    p99_9_ns: u64,
+}
+
+impl Merged {
+    // Several threads can share a name (e.g. a worker pool): counts and totals add up, the
+    // percentiles can't be merged without the histograms, so the worst thread's are kept
+    fn add(&mut self, stat: &StatReport) {
+        self.count += stat.count;
+        self.total_ns += stat.total_ns;
+        self.max_ns = self.max_ns.max(stat.max_ns);
+        self.p50_ns = self.p50_ns.max(stat.p50_ns);
+        self.p90_ns = self.p90_ns.max(stat.p90_ns);
+        self.p99_ns = self.p99_ns.max(stat.p99_ns);
+        self.p99_9_ns = self.p99_9_ns.max(stat.p99_9_ns);
+    }
+}
+
+struct Row {
+    key: Key,
+    base: Merged,
+    new: Merged,
+}
+
+impl Row {
+    fn total_delta(&self) -> i128 {
+        self.new.total_ns as i128 - self.base.total_ns as i128
+    }
+
+    // Grew by more than `threshold` percent, in total time or in tail latency
+    fn regressed(&self, threshold: f64) -> bool {
+        let grew = |base: u64, new: u64| {
+            new > base && (base == 0 || (new - base) as f64 * 100.0 / base as f64 > threshold)
+        };
+        grew(self.base.total_ns, self.new.total_ns) || grew(self.base.p99_ns, self.new.p99_ns)
+    }
+}
+
+/// `diff` subcommand: compare two recordings or JSON reports.
+pub fn run(args: &DiffArgs) -> anyhow::Result<()> {
+    let base = load(&args.base)?;
+    let new = load(&args.new)?;
+
+    let mut rows: HashMap<Key, Row> = HashMap::new();
+    for (processes, is_new) in [(&base, false), (&new, true)] {
+        for (key, stat) in flatten(processes) {
+            let row = rows.entry(key.clone()).or_insert_with(|| Row {
+                key,
+                base: Merged::default(),
+                new: Merged::default(),
+            });
+            if is_new {
+                row.new.add(stat);
+            } else {
+                row.base.add(stat);
+            }
+        }
+    }
+
+    // Biggest regressions first, improvements last
+    let mut rows: Vec<_> = rows.into_values().collect();
+    rows.sort_by(|a, b| {
+        b.total_delta()
+            .cmp(&a.total_delta())
+            .then_with(|| a.key.cmp(&b.key))
+    });
+
+    let color = std::io::stdout().is_terminal();
+    println!("# Base: {}", args.base.display());
+    println!("# New:  {}", args.new.display());
+    println!(
+        "# Rows marked with ! grew by more than {}% in total time or P99",
+        args.threshold
+    );
+    println!();
+    let header = format!(
+        "  {:<16} {:<16} {:<13} {:<20} {:<18} {:<24} {:<20} {:<20} {:<20} {:<20} {:<20}",
+        "PROCESS",
+        "THREAD",
+        "KIND",
+        "NAME",
+        "COUNT",
+        "TOTAL ns",
+        "MAX ns",
+        "P50 ns",
+        "P90 ns",
+        "P99 ns",
+        "P99.9 ns"
+    );
+    println!("{}", header.trim_end());
+    for row in &rows {
+        let regressed = row.regressed(args.threshold);
+        let (process, thread, kind, name) = &row.key;
+        let line = format!(
+            "{} {:<16} {:<16} {:<13} {:<20} {:<18} {:<24} {:<20} {:<20} {:<20} {:<20} {:<20}",
+            if regressed { "!" } else { " " },
+            process,
+            thread,
+            kind,
+            name,
+            format_delta(row.base.count, row.new.count),
+            format_delta(row.base.total_ns, row.new.total_ns),
+            format_delta(row.base.max_ns, row.new.max_ns),
+            format_delta(row.base.p50_ns, row.new.p50_ns),
+            format_delta(row.base.p90_ns, row.new.p90_ns),
+            format_delta(row.base.p99_ns, row.new.p99_ns),
+            format_delta(row.base.p99_9_ns, row.new.p99_9_ns),
+        );
+        if regressed && color {
+            println!("\x1b[31m{}\x1b[0m", line.trim_end());
+        } else {
+            println!("{}", line.trim_end());
+        }
+    }
+    Ok(())
+}
+
+// The processes of a recording (replayed) or of a JSON report
+fn load(path: &Path) -> anyhow::Result<Vec<ProcessReport>> {
+    if recording::is_recording(path) {
+        procfs::go_offline();
+        let mut reader = Reader::open(path)?;
+        syscalls::set_arch(&reader.header.arch);
+        let stats = reader.read_stats(0)?;
+        let mut pids: Vec<_> = stats.processes.keys().copied().collect();
+        pids.sort();
+        return Ok(report::processes(&stats, &pids, false));
+    }
+
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let report: JsonReport = serde_json::from_str(&text).with_context(|| {
+        format!(
+            "{} is neither a recording nor a JSON report (--format json without --interval)",
+            path.display()
+        )
+    })?;
+    if report.version != report::JSON_VERSION {
+        bail!(
+            "{} is a version {} JSON report, expected version {}",
+            path.display(),
+            report.version,
+            report::JSON_VERSION
+        );
+    }
+    Ok(report.processes)
+}
+
+fn flatten(processes: &[ProcessReport]) -> Vec<(Key, &StatReport)> {
+    let mut rows = Vec::new();
+    for process in processes {
+        for thread in &process.threads {
+            let key = |kind: &'static str, name: &str| {
+                (
+                    process.name.clone(),
+                    thread.name.clone(),
+                    kind,
+                    name.to_string(),
+                )
+            };
+            for (kind, resources) in [
+                ("syscall", &thread.syscalls),
+                ("hardirq", &thread.hardirqs),
+                ("softirq", &thread.softirqs),
+            ] {
+                rows.extend(resources.iter().map(|r| (key(kind, &r.name), &r.stat)));
+            }
+            // Failures as `read:EAGAIN`
+            for syscall in &thread.syscalls {
+                rows.extend(syscall.errors.iter().map(|error| {
+                    let name = format!("{}:{}", syscall.name, error.name);
+                    (key("syscall_error", &name), &error.stat)
+                }));
+            }
+            for (kind, stat) in [("run", &thread.run), ("ready", &thread.ready)] {
+                rows.extend(stat.iter().map(|stat| (key(kind, ""), stat)));
+            }
+        }
+    }
+    rows
+}
+
+// `+1,234 (+12%)`, `(new)`/`(gone)` instead of the percentage for rows missing on one side
+fn format_delta(base: u64, new: u64) -> String {
+    let delta = new as i128 - base as i128;
+    let sign = if delta < 0 { "-" } else { "+" };
+    let delta_text = format!("{sign}{}", format_number(delta.unsigned_abs() as u64));
+    if base == new {
+        "=".to_string()
+    } else if base == 0 {
+        format!("{delta_text} (new)")
+    } else if new == 0 {
+        format!("{delta_text} (gone)")
+    } else {
+        let percent = delta as f64 * 100.0 / base as f64;
+        format!("{delta_text} ({percent:+.0}%)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(base: (u64, u64), new: (u64, u64)) -> Row {
+        let merged = |(total_ns, p99_ns)| Merged {
+            total_ns,
+            p99_ns,
+            ..Default::default()
+        };
+        Row {
+            key: Default::default(),
+            base: merged(base),
+            new: merged(new),
+        }
+    }
+
+    #[test]
+    fn delta_sign_and_percent() {
+        assert_eq!(format_delta(1000, 1000), "=");
+        assert_eq!(format_delta(1000, 1500), "+500 (+50%)");
+        assert_eq!(format_delta(1500, 1000), "-500 (-33%)");
+        assert_eq!(format_delta(1_000_000, 3_470_000), "+2,470,000 (+247%)");
+    }
+
+    #[test]
+    fn delta_against_zero() {
+        assert_eq!(format_delta(0, 1234), "+1,234 (new)");
+        assert_eq!(format_delta(1234, 0), "-1,234 (gone)");
+        assert_eq!(format_delta(0, 0), "=");
+    }
+
+    #[test]
+    fn regressed_beyond_threshold() {
+        // Total time or p99 has to grow by more than the threshold
+        assert!(!row((1000, 100), (1100, 100)).regressed(10.0));
+        assert!(row((1000, 100), (1101, 100)).regressed(10.0));
+        assert!(row((1000, 100), (1000, 111)).regressed(10.0));
+        assert!(!row((1000, 100), (500, 50)).regressed(0.0));
+        assert!(row((1000, 100), (1001, 100)).regressed(0.0));
+    }
+
+    #[test]
+    fn regressed_from_zero() {
+        assert!(row((0, 0), (1, 0)).regressed(1000.0));
+        assert!(!row((0, 0), (0, 0)).regressed(0.0));
+    }
+}
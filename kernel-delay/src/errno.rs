@@ -0,0 +1,186 @@
+// Names of the Linux errno values, indexed by number (asm-generic/errno-base.h and errno.h,
+// which x86_64, aarch64 and riscv64 share). Aliases (EWOULDBLOCK, EDEADLOCK) are left out.
+const NAMES: [&str; 134] = [
+    "",
+    "EPERM",
+    "ENOENT",
+    "ESRCH",
+    "EINTR",
+    "EIO",
+    "ENXIO",
+    "E2BIG",
+    "ENOEXEC",
+    "EBADF",
+    "ECHILD",
+    "EAGAIN",
+    "ENOMEM",
+    "EACCES",
+    "EFAULT",
+    "ENOTBLK",
+    "EBUSY",
+    "EEXIST",
+    "EXDEV",
+    "ENODEV",
+    "ENOTDIR",
+    "EISDIR",
+    "EINVAL",
+    "ENFILE",
+    "EMFILE",
+    "ENOTTY",
+    "ETXTBSY",
+    "EFBIG",
+    "ENOSPC",
+    "ESPIPE",
+    "EROFS",
+    "EMLINK",
+    "EPIPE",
+    "EDOM",
+    "ERANGE",
+    "EDEADLK",
+    "ENAMETOOLONG",
+    "ENOLCK",
+    "ENOSYS",
+    "ENOTEMPTY",
+    "ELOOP",
+    "",
+    "ENOMSG",
+    "EIDRM",
+    "ECHRNG",
+    "EL2NSYNC",
+    "EL3HLT",
+    "EL3RST",
+    "ELNRNG",
+    "EUNATCH",
+    "ENOCSI",
+    "EL2HLT",
+    "EBADE",
+    "EBADR",
+    "EXFULL",
+    "ENOANO",
+    "EBADRQC",
+    "EBADSLT",
+    "",
+    "EBFONT",
+    "ENOSTR",
+    "ENODATA",
+    "ETIME",
+    "ENOSR",
+    "ENONET",
+    "ENOPKG",
+    "EREMOTE",
+    "ENOLINK",
+    "EADV",
+    "ESRMNT",
+    "ECOMM",
+    "EPROTO",
+    "EMULTIHOP",
+    "EDOTDOT",
+    "EBADMSG",
+    "EOVERFLOW",
+    "ENOTUNIQ",
+    "EBADFD",
+    "EREMCHG",
+    "ELIBACC",
+    "ELIBBAD",
+    "ELIBSCN",
+    "ELIBMAX",
+    "ELIBEXEC",
+    "EILSEQ",
+    "ERESTART",
+    "ESTRPIPE",
+    "EUSERS",
+    "ENOTSOCK",
+    "EDESTADDRREQ",
+    "EMSGSIZE",
+    "EPROTOTYPE",
+    "ENOPROTOOPT",
+    "EPROTONOSUPPORT",
+    "ESOCKTNOSUPPORT",
+    "EOPNOTSUPP",
+    "EPFNOSUPPORT",
+    "EAFNOSUPPORT",
+    "EADDRINUSE",
+    "EADDRNOTAVAIL",
+    "ENETDOWN",
+    "ENETUNREACH",
+    "ENETRESET",
+    "ECONNABORTED",
+    "ECONNRESET",
+    "ENOBUFS",
+    "EISCONN",
+    "ENOTCONN",
+    "ESHUTDOWN",
+    "ETOOMANYREFS",
+    "ETIMEDOUT",
+    "ECONNREFUSED",
+    "EHOSTDOWN",
+    "EHOSTUNREACH",
+    "EALREADY",
+    "EINPROGRESS",
+    "ESTALE",
+    "EUCLEAN",
+    "ENOTNAM",
+    "ENAVAIL",
+    "EISNAM",
+    "EREMOTEIO",
+    "EDQUOT",
+    "ENOMEDIUM",
+    "EMEDIUMTYPE",
+    "ECANCELED",
+    "ENOKEY",
+    "EKEYEXPIRED",
+    "EKEYREVOKED",
+    "EKEYREJECTED",
+    "EOWNERDEAD",
+    "ENOTRECOVERABLE",
+    "ERFKILL",
+    "EHWPOISON",
+];
+
+/// Symbolic name of an errno, e.g. `EAGAIN` for 11.
+pub fn name(errno: u32) -> String {
+    match errno {
+        // Kernel internal codes, seen by the syscall exit tracepoints before signal handling
+        // restarts the syscall or turns them into EINTR
+        512 => "ERESTARTSYS".to_string(),
+        513 => "ERESTARTNOINTR".to_string(),
+        514 => "ERESTARTNOHAND".to_string(),
+        516 => "ERESTART_RESTARTBLOCK".to_string(),
+        _ => NAMES
+            .get(errno as usize)
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("errno_{errno}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_by_number() {
+        assert_eq!(name(1), "EPERM");
+        assert_eq!(name(2), "ENOENT");
+        assert_eq!(name(11), "EAGAIN");
+        assert_eq!(name(110), "ETIMEDOUT");
+        assert_eq!(name(133), "EHWPOISON");
+    }
+
+    #[test]
+    fn kernel_internal_codes() {
+        assert_eq!(name(512), "ERESTARTSYS");
+        assert_eq!(name(513), "ERESTARTNOINTR");
+        assert_eq!(name(514), "ERESTARTNOHAND");
+        assert_eq!(name(516), "ERESTART_RESTARTBLOCK");
+    }
+
+    #[test]
+    fn unknown_numbers() {
+        // Gaps in the table and anything past it
+        assert_eq!(name(0), "errno_0");
+        assert_eq!(name(41), "errno_41");
+        assert_eq!(name(134), "errno_134");
+        assert_eq!(name(515), "errno_515");
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    env,
+    ffi::CString,
+    fs::File,
+    io,
+    os::{
+        fd::AsRawFd as _,
+        unix::{
+            ffi::{OsStrExt as _, OsStringExt as _},
+            fs::PermissionsExt as _,
+        },
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+
+/// A command forked by us and held stopped until the eBPF programs know its PID.
+#[derive(Debug)]
+pub struct Child {
+    pub pid: u32,
+}
+
+impl Child {
+    /// Fork `argv`, stop the child with SIGSTOP before it execs and wait until it is stopped.
+    ///
+    /// `std::process::Command` can't be used for this: `spawn()` only returns once the child
+    /// has exec'd, which it never does while stopped.
+    pub fn spawn_stopped(argv: &[String]) -> anyhow::Result<Self> {
+        if argv.is_empty() {
+            bail!("no command given");
+        }
+        // Everything the child needs is prepared before forking: it may only call
+        // async-signal-safe functions afterwards, which execvp (searching PATH) is not
+        let program = find_program(&argv[0])?;
+        let program = CString::new(program.as_os_str().as_bytes())
+            .context("command path must not contain NUL bytes")?;
+        let args = argv
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("command arguments must not contain NUL bytes")?;
+        let mut arg_ptrs: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        arg_ptrs.push(ptr::null());
+        let env = env::vars_os()
+            .filter_map(|(name, value)| {
+                let mut var = name.into_vec();
+                var.push(b'=');
+                var.extend_from_slice(value.as_bytes());
+                CString::new(var).ok()
+            })
+            .collect::<Vec<_>>();
+        let mut env_ptrs: Vec<*const libc::c_char> = env.iter().map(|var| var.as_ptr()).collect();
+        env_ptrs.push(ptr::null());
+        let error = b"kernel-delay: failed to execute command\n";
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("fork failed");
+        }
+        if pid == 0 {
+            unsafe {
+                libc::raise(libc::SIGSTOP);
+                libc::execve(program.as_ptr(), arg_ptrs.as_ptr(), env_ptrs.as_ptr());
+                libc::write(2, error.as_ptr().cast(), error.len());
+                libc::_exit(127);
+            }
+        }
+
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) } < 0 {
+            return Err(io::Error::last_os_error()).context("waitpid failed");
+        }
+        if !libc::WIFSTOPPED(status) {
+            bail!("command exited before it could be traced");
+        }
+
+        Ok(Self { pid: pid as u32 })
+    }
+
+    /// Let the stopped child continue into exec.
+    pub fn resume(&self) -> anyhow::Result<()> {
+        if unsafe { libc::kill(self.pid as i32, libc::SIGCONT) } < 0 {
+            return Err(io::Error::last_os_error()).context("failed to resume command");
+        }
+        Ok(())
+    }
+
+    /// Pass a signal on to the child. Errors are ignored, it may have exited already.
+    pub fn signal(&self, signal: i32) {
+        unsafe { libc::kill(self.pid as i32, signal) };
+    }
+
+    /// Whether the child is in the foreground process group of our controlling terminal, where
+    /// the terminal sends Ctrl-C to.
+    pub fn in_terminal_foreground(&self) -> bool {
+        let Ok(tty) = File::open("/dev/tty") else {
+            return false;
+        };
+        let foreground = unsafe { libc::tcgetpgrp(tty.as_raw_fd()) };
+        let group = unsafe { libc::getpgid(self.pid as i32) };
+        foreground >= 0 && foreground == group
+    }
+
+    /// The exit code of the child if it has terminated, shell style (128 + signal number when it
+    /// was killed by a signal).
+    pub fn try_wait(&self) -> anyhow::Result<Option<i32>> {
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(self.pid as i32, &mut status, libc::WNOHANG) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error()).context("waitpid failed");
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+        if libc::WIFEXITED(status) {
+            Ok(Some(libc::WEXITSTATUS(status)))
+        } else if libc::WIFSIGNALED(status) {
+            Ok(Some(128 + libc::WTERMSIG(status)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Path of the program to execute: a name with a slash as it is, anything else looked up in PATH
+// like the shell does
+fn find_program(name: &str) -> anyhow::Result<PathBuf> {
+    if name.contains('/') {
+        return Ok(PathBuf::from(name));
+    }
+    let path = env::var_os("PATH").unwrap_or_else(|| "/usr/local/bin:/usr/bin:/bin".into());
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| anyhow!("command not found: {name}"))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Don't leave a stopped command behind when setting up the tracing fails
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Ok(None) = self.try_wait() {
+            unsafe {
+                libc::kill(self.pid as i32, libc::SIGKILL);
+                libc::waitpid(self.pid as i32, ptr::null_mut(), 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    fn wait(child: &Child) -> i32 {
+        for _ in 0..500 {
+            if let Some(code) = child.try_wait().unwrap() {
+                return code;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("command did not exit");
+    }
+
+    #[test]
+    fn finds_programs_in_path() {
+        assert!(find_program("sh").unwrap().is_absolute());
+        assert_eq!(find_program("./run.sh").unwrap(), Path::new("./run.sh"));
+        assert!(find_program("kernel-delay-no-such-command").is_err());
+    }
+
+    #[test]
+    fn runs_command_with_environment() {
+        let argv = ["sh", "-c", "test \"$HOME\" = \"$1\" && exit 3", "sh"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(env::var("HOME").ok())
+            .collect::<Vec<_>>();
+        let child = Child::spawn_stopped(&argv).unwrap();
+        child.resume().unwrap();
+        assert_eq!(wait(&child), 3);
+    }
+}
@@ -1,53 +1,293 @@
-use aya::maps::{HashMap as AyaHashMap, ring_buf::RingBuf};
-use aya::programs::TracePoint;
-#[rustfmt::skip]
-use log::{debug, warn, info};
+use aya::maps::{StackTraceMap, ring_buf::RingBuf};
+use log::{info, warn};
 use clap::Parser;
 use std::collections::HashMap as StdHashMap;
 use std::convert::TryFrom;
-use std::thread;
+use std::path::PathBuf;
 use std::time::Duration;
-use tokio::signal;
+use tokio::signal::unix::{SignalKind, signal};
 
 // Import the Event structure from the common crate
-use kernel_delay_common::{Event, EventType, SyscallStat, ThreadReadyStat, ThreadRunStat};
+use kernel_delay_common::{Event, MAX_ERRNO, hist_slot_range};
+
+use crate::{
+    launch::Child,
+    ringbuf::EventReader,
+    stacks::Stacks,
+    stats::{ProcessStats, Stat, Stats, ThreadStats},
+    tracer::Tracer,
+};
+
+mod cgroup;
+mod diff;
+mod errno;
+mod launch;
+mod metrics;
+mod procfs;
+mod recording;
+mod report;
+mod ringbuf;
+mod serve;
+mod stacks;
+mod stats;
+mod syscalls;
+mod tracefs;
+mod tracer;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Accumulate count/total/max per thread and resource in the kernel, read at the end
+    Aggregate,
+    /// Stream every single measurement to userspace and aggregate there
+    Trace,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Human readable tables
+    Text,
+    /// A single JSON document, see the README for its layout
+    Json,
+    /// One comma separated line per thread and resource, with a header line
+    Csv,
+    /// Like csv, tab separated
+    Tsv,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TopBy {
+    /// Total time spent in syscalls
+    Syscall,
+    /// Total time spent waiting on the run queue
+    Runq,
+    /// Total time interrupted by hard and soft IRQs
+    Irq,
+}
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    group(
+        clap::ArgGroup::new("target")
+            .args(["pids", "cgroups", "units", "container_ids", "all", "command"])
+            .multiple(true)
+            .required(true)
+    )
+)]
 struct Args {
-    /// PID of the process to monitor
-    #[clap(short, long)]
-    pid: u32,
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+    #[clap(flatten)]
+    trace: TraceArgs,
+    #[clap(flatten)]
+    output: OutputArgs,
     #[clap(short, long, default_value = "10")] // default
-    /// Duration for which to monitor the process
+    /// Duration for which to monitor the process, 0 to run until interrupted
     duration: u64,
+    /// Print a report of the last interval every this many seconds, besides the final one
+    #[clap(
+        short,
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: Option<u64>,
+    /// Where measurements are aggregated
+    #[clap(long, value_enum, default_value = "aggregate")]
+    mode: Mode,
+    /// Also write every event to this file, to be analyzed later with `kernel-delay report`.
+    /// Implies --mode trace.
+    #[clap(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Size of the ring buffer events are streamed through in trace mode (e.g. 4M), a power of 2
+    /// multiple of the page size. Raise it when events are lost. [default: 1M]
+    #[clap(long, value_name = "BYTES", value_parser = parse_ringbuf_size)]
+    ringbuf_size: Option<u32>,
+    /// With --slow, also capture the user stacks of the slow syscalls and the kernel and user
+    /// stacks of the slow softirqs, printed under every slow event and per syscall and stack.
+    /// Not kept in recordings.
+    #[clap(long, requires = "slow", conflicts_with = "record")]
+    stacks: bool,
+    /// Command to launch and monitor until it exits (after `--`). Its exit code is propagated.
+    #[clap(last = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Keep monitoring and expose the statistics as Prometheus metrics over HTTP
+    Serve(ServeArgs),
+    /// Print the report of a file written with --record
+    Report(ReportArgs),
+    /// Compare two recordings or JSON reports, biggest regressions first
+    Diff(DiffArgs),
+}
+
+#[derive(clap::Args, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("target")
+        .args(["pids", "cgroups", "units", "container_ids", "all"])
+        .multiple(true)
+        .required(true)
+))]
+struct ServeArgs {
+    /// Address to serve /metrics on
+    #[clap(long, default_value = "127.0.0.1:9633", value_name = "ADDR")]
+    listen: std::net::SocketAddr,
+    #[clap(flatten)]
+    trace: TraceArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    /// Recording to read
+    #[clap(value_name = "FILE")]
+    file: PathBuf,
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Recording or JSON report to compare against
+    #[clap(value_name = "BASE")]
+    base: PathBuf,
+    /// Recording or JSON report to compare
+    #[clap(value_name = "NEW")]
+    new: PathBuf,
+    /// Mark rows whose total time or P99 grew by more than this many percent
+    #[clap(long, default_value = "10", value_name = "PERCENT")]
+    threshold: f64,
+}
+
+// How the report is printed, common to monitoring and replaying a recording
+#[derive(clap::Args, Debug)]
+struct OutputArgs {
+    /// With --all, number of processes in the summary table and detailed per thread
+    #[clap(long, default_value = "10", value_name = "N")]
+    top: usize,
+    /// With --all, what the summary table is ranked by
+    #[clap(long, value_enum, default_value = "syscall")]
+    top_by: TopBy,
+    /// Output format of the report
+    #[clap(long, value_enum, default_value = "text")]
+    format: Format,
+    /// Print the latency distribution under every row (in JSON: include the histogram slots)
+    #[clap(long)]
+    histogram: bool,
+    /// Print the failed syscalls of every thread per errno, under its syscall table
+    #[clap(long)]
+    errors: bool,
+    /// Also list every syscall, softirq and run queue wait that took at least this long (e.g.
+    /// 3ms, 500us), with the time it started at. Not available with --format csv/tsv.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration_ns)]
+    slow: Option<u64>,
+}
+
+// What to trace and how, common to monitoring and serve
+#[derive(clap::Args, Debug)]
+struct TraceArgs {
+    /// PID of a process to monitor, can be repeated
+    #[clap(short, long = "pid")]
+    pids: Vec<u32>,
+    /// Also monitor all descendants of the given processes, including the ones forked while
+    /// monitoring
+    #[clap(long)]
+    pid_tree: bool,
+    /// cgroup (v2) to monitor, as a path below /sys/fs/cgroup or relative to it, can be
+    /// repeated. Covers every task in the cgroup and its descendants, whenever it appears.
+    #[clap(long = "cgroup", value_name = "PATH")]
+    cgroups: Vec<String>,
+    /// systemd unit whose cgroup to monitor (e.g. nginx or user@1000.service), can be repeated
+    #[clap(long = "unit", value_name = "NAME")]
+    units: Vec<String>,
+    /// Container whose cgroup to monitor, by full or abbreviated ID, can be repeated
+    #[clap(long = "container-id", value_name = "ID")]
+    container_ids: Vec<String>,
+    /// Monitor every task on the system (except kernel-delay itself)
+    #[clap(long, conflicts_with_all = ["pids", "pid_tree", "cgroups", "units", "container_ids"])]
+    all: bool,
+    /// Only trace these syscalls (comma separated, e.g. read,write,futex) through their
+    /// per-syscall tracepoints instead of every syscall through raw_syscalls. Lower overhead.
+    #[clap(long, value_delimiter = ',')]
+    syscalls: Vec<String>,
+    /// Use linear histogram slots of this many nanoseconds instead of power-of-2 slots
+    #[clap(long, value_name = "NS")]
+    hist_linear: Option<u64>,
 }
 
 fn get_thread_name(thread_name_bytes: &[u8; 16], tid: u32, pid: u32) -> String {
-    // First, try to get the real thread name from the system
-    let comm_path = format!("/proc/{}/task/{}/comm", pid, tid);
-    if let Ok(name) = std::fs::read_to_string(&comm_path) {
-        let trimmed = name.trim();
-        if !trimmed.is_empty() {
-            return trimmed.to_string();
-        }
-    }
-    
-    // Fallback to the original method
-    // Convert bytes to string, trimming null bytes
+    // The name captured by the eBPF programs, which outlives the thread
     let name_str = String::from_utf8_lossy(thread_name_bytes);
     let trimmed = name_str.trim_end_matches('\0');
-    
-    // If the name is empty or generic, use a default
-    if trimmed.is_empty() || trimmed == "thread" {
-        "unnamed-thread".to_string()
-    } else {
-        trimmed.to_string()
+
+    // Empty when the thread was never seen running, "thread" in recordings of older versions
+    if !trimmed.is_empty() && trimmed != "thread" {
+        return trimmed.to_string();
+    }
+
+    // Fall back to the system while the thread is still around
+    procfs::thread_name(pid, tid).unwrap_or_else(|| "unnamed-thread".to_string())
+}
+
+fn get_process_name(pid: u32, process: &ProcessStats) -> String {
+    procfs::process_name(pid)
+        .or_else(|| {
+            process
+                .threads
+                .get(&pid)
+                .map(|thread| get_thread_name(&thread.thread_name, pid, pid))
+        })
+        .unwrap_or_else(|| "exited".to_string())
+}
+
+// PIDs of the `top` processes with the most delay of the given kind, worst first
+fn top_processes(stats: &Stats, top: usize, top_by: TopBy) -> Vec<u32> {
+    let metric = |process: &ProcessStats| match top_by {
+        TopBy::Syscall => process.syscall_ns(),
+        TopBy::Runq => process.runq_ns(),
+        TopBy::Irq => process.irq_ns(),
+    };
+    let mut ranked: Vec<_> = stats.processes.iter().collect();
+    ranked.sort_by_key(|(_, process)| std::cmp::Reverse(metric(process)));
+    ranked.into_iter().take(top).map(|(&pid, _)| pid).collect()
+}
+
+fn print_process_summary(stats: &Stats, pids: &[u32], top_by: TopBy) {
+    println!();
+    let ranked_by = match top_by {
+        TopBy::Syscall => "SYSCALL TIME",
+        TopBy::Runq => "RUN QUEUE DELAY",
+        TopBy::Irq => "IRQ TIME",
+    };
+    println!("[TOP {} PROCESSES BY {}]", pids.len(), ranked_by);
+    println!(
+        "{:<10} {:<16} {:<8} {:<17} {:<17} {:<17}",
+        "PID", "PROCESS", "THREADS", "SYSCALL ns", "RUNQ ns", "IRQ ns"
+    );
+    for &pid in pids {
+        let process = &stats.processes[&pid];
+        println!(
+            "{:<10} {:<16} {:<8} {:<17} {:<17} {:<17}",
+            pid,
+            get_process_name(pid, process),
+            process.threads.len(),
+            format_number(process.syscall_ns()),
+            format_number(process.runq_ns()),
+            format_number(process.irq_ns())
+        );
     }
 }
 
-fn print_thread_statistics(thread_events: &StdHashMap<u32, Vec<Event>>, target_pid: u32) {
-    if thread_events.is_empty() {
+fn print_thread_statistics(stats: &Stats, pids: &[u32], show_histogram: bool, show_errors: bool) {
+    let interrupt_names = procfs::interrupt_names();
+
+    if stats.is_empty() {
         println!("No events captured during monitoring period.");
         println!("This could be because:");
         println!("  1. The target process was idle during monitoring");
@@ -57,552 +297,357 @@ fn print_thread_statistics(thread_events: &StdHashMap<u32, Vec<Event>>, target_p
         return;
     }
     
-    println!("TID        THREAD           <RESOURCE SPECIFIC>");
-    println!("{:-<10} {:-<16} {:-<76}", "", "", "");
-
-    // Sort threads by TID for consistent output
-    let mut sorted_threads: Vec<_> = thread_events.keys().collect();
-    sorted_threads.sort();
-
-    for &tid in sorted_threads {
-        if let Some(events) = thread_events.get(&tid) {
-            if let Some(first_event) = events.first() {
-                // Get thread name from the event or system
-                let thread_name = get_thread_name(&first_event.thread_name, tid, target_pid);
-                
-                // Print thread header
-                println!("{:<10} {:<16} [SYSCALL STATISTICS]", tid, thread_name);
-
-                // Collect and aggregate statistics
-                let mut syscall_stats: StdHashMap<u32, SyscallStat> = StdHashMap::new();
-                let mut thread_run_stats: Vec<ThreadRunStat> = Vec::new();
-                let mut thread_ready_stats: Vec<ThreadReadyStat> = Vec::new();
-                let mut total_excluding_poll = 0u64;
-                let mut softirq_stats: StdHashMap<u32, kernel_delay_common::IrqStat> = StdHashMap::new();
-
-                for event in events {
-                    match event.event_type {
-                        x if x == EventType::SyscallStats as u32 => {
-                            // Aggregate syscall statistics by syscall number
-                            let syscall_number = event.syscall_stat.number;
-                            syscall_stats.entry(syscall_number)
-                                .and_modify(|stat| {
-                                    stat.count += event.syscall_stat.count;
-                                    stat.total_ns += event.syscall_stat.total_ns;
-                                    if event.syscall_stat.max_ns > stat.max_ns {
-                                        stat.max_ns = event.syscall_stat.max_ns;
-                                    }
-                                })
-                                .or_insert(event.syscall_stat);
-                            total_excluding_poll += event.total_excluding_poll;
-                        }
-                        x if x == EventType::ThreadRunStats as u32 => {
-                            thread_run_stats.push(event.thread_run_stat);
-                        }
-                        x if x == EventType::ThreadReadyStats as u32 => {
-                            thread_ready_stats.push(event.thread_ready_stat);
-                        }
-                        x if x == EventType::SoftIrqStats as u32 => {
-                            // Aggregate softirq statistics by vector
-                            let vector = event.irq_stat.vector;
-                            softirq_stats.entry(vector)
-                                .and_modify(|stat| {
-                                    stat.count += event.irq_stat.count;
-                                    stat.total_ns += event.irq_stat.total_ns;
-                                    if event.irq_stat.max_ns > stat.max_ns {
-                                        stat.max_ns = event.irq_stat.max_ns;
-                                    }
-                                })
-                                .or_insert(event.irq_stat);
-                        }
-                        _ => {}
-                    }
-                }
+    // Group threads by process
+    for &pid in pids {
+        let Some(process) = stats.processes.get(&pid) else {
+            continue;
+        };
+        let process_name = get_process_name(pid, process);
+        println!();
+        println!("PID {pid} ({process_name})");
+        println!("TID        THREAD           <RESOURCE SPECIFIC>");
+        println!("{:-<10} {:-<16} {:-<76}", "", "", "");
+
+        // Sort threads by TID for consistent output
+        let mut sorted_threads: Vec<_> = process.threads.keys().collect();
+        sorted_threads.sort();
+
+        for &tid in sorted_threads {
+            let thread = &process.threads[&tid];
+
+            // Get thread name from the event or system
+            let thread_name = get_thread_name(&thread.thread_name, tid, pid);
 
-                // Print syscall statistics header
-                if !syscall_stats.is_empty() {
+            // Print thread header
+            println!("{:<10} {:<16} [SYSCALL STATISTICS]", tid, thread_name);
+
+            // Print syscall statistics header
+            if !thread.syscalls.is_empty() {
+                println!(
+                    "           {:<20} {:<11} {:<13} {:<11} {:<17} {:<13} {}",
+                    "NAME", "NUMBER", "COUNT", "ERRORS", "TOTAL ns", "MAX ns", PERCENTILE_HEADER
+                );
+
+                // Sort syscall stats by total time (descending) for better readability
+                let mut sorted_syscall_stats: Vec<_> = thread.syscalls.iter().collect();
+                sorted_syscall_stats.sort_by_key(|(_, stat)| std::cmp::Reverse(stat.total_ns));
+
+                for (&number, stat) in sorted_syscall_stats {
+                    let name = get_syscall_name(number);
+                    let errors: u64 = report::syscall_errors(thread, number)
+                        .iter()
+                        .map(|(_, error)| error.count)
+                        .sum();
                     println!(
-                        "           {:<20} {:<11} {:<13} {:<17} {:<13}",
-                        "NAME", "NUMBER", "COUNT", "TOTAL ns", "MAX ns"
+                        "           {:<20} {:<11} {:<13} {:<11} {:<17} {:<13} {}",
+                        name,
+                        number,
+                        stat.count,
+                        errors,
+                        format_number(stat.total_ns),
+                        format_number(stat.max_ns),
+                        format_percentiles(stat, stats.hist_step_ns)
                     );
-                    
-                    // Sort syscall stats by total time (descending) for better readability
-                    let mut sorted_syscall_stats: Vec<_> = syscall_stats.values().collect();
-                    sorted_syscall_stats.sort_by(|a, b| b.total_ns.cmp(&a.total_ns));
-                    
-                    for stat in sorted_syscall_stats {
-                        let name = get_syscall_name(stat.number);
-                        println!(
-                            "           {:<20} {:<11} {:<13} {:<17} {:<13}",
-                            name,
-                            stat.number,
-                            stat.count,
-                            format_number(stat.total_ns),
-                            format_number(stat.max_ns)
-                        );
+                    if show_histogram {
+                        print_histogram(stat, stats.hist_step_ns);
                     }
-                    println!(
-                        "           TOTAL( - poll): {:<37} {:<13}",
-                        "",
-                        format_number(total_excluding_poll)
-                    );
-                    println!("");
                 }
 
-                // Print thread run statistics
-                if !thread_run_stats.is_empty() {
-                    println!("           [THREAD RUN STATISTICS]");
-                    println!(
-                        "           {:<19} {:<17} {:<17} {:<13}",
-                        "SCHED_CNT", "TOTAL ns", "MIN ns", "MAX ns"
-                    );
-                    
-                    // Aggregate thread run statistics
-                    if !thread_run_stats.is_empty() {
-                        let first_stat = &thread_run_stats[0];
-                        let mut aggregated_stat = ThreadRunStat {
-                            sched_cnt: 0,
-                            total_ns: 0,
-                            min_ns: first_stat.min_ns,
-                            max_ns: 0,
-                        };
-                        
-                        for stat in &thread_run_stats {
-                            aggregated_stat.sched_cnt += stat.sched_cnt;
-                            aggregated_stat.total_ns += stat.total_ns;
-                            if stat.min_ns < aggregated_stat.min_ns {
-                                aggregated_stat.min_ns = stat.min_ns;
-                            }
-                            if stat.max_ns > aggregated_stat.max_ns {
-                                aggregated_stat.max_ns = stat.max_ns;
-                            }
-                        }
-                        
-                        println!(
-                            "           {:<19} {:<17} {:<17} {:<13}",
-                            aggregated_stat.sched_cnt,
-                            format_number(aggregated_stat.total_ns),
-                            format_number(aggregated_stat.min_ns),
-                            format_number(aggregated_stat.max_ns)
-                        );
-                    }
-                    println!("");
+                // Blocking in poll is usually idle time rather than a delay, so leave it out
+                let total_excluding_poll: u64 = thread
+                    .syscalls
+                    .iter()
+                    .filter(|&(&number, _)| !matches!(get_syscall_name(number).as_str(), "poll" | "ppoll"))
+                    .map(|(_, stat)| stat.total_ns)
+                    .sum();
+                println!(
+                    "           TOTAL( - poll): {:<49} {:<13}",
+                    "",
+                    format_number(total_excluding_poll)
+                );
+                println!();
+
+                if show_errors && !thread.syscall_errors.is_empty() {
+                    print_syscall_errors(thread, stats.hist_step_ns, show_histogram);
+                }
+            }
+
+            // Print thread run statistics
+            if thread.run.count > 0 {
+                println!("           [THREAD RUN STATISTICS]");
+                println!(
+                    "           {:<19} {:<17} {:<17} {:<13} {}",
+                    "SCHED_CNT", "TOTAL ns", "MIN ns", "MAX ns", PERCENTILE_HEADER
+                );
+                println!(
+                    "           {:<19} {:<17} {:<17} {:<13} {}",
+                    thread.run.count,
+                    format_number(thread.run.total_ns),
+                    format_number(thread.run.min_ns),
+                    format_number(thread.run.max_ns),
+                    format_percentiles(&thread.run, stats.hist_step_ns)
+                );
+                if show_histogram {
+                    print_histogram(&thread.run, stats.hist_step_ns);
                 }
+                println!();
+            }
+
+            // Print thread ready statistics
+            if thread.ready.count > 0 {
+                println!("           [THREAD READY STATISTICS]");
+                println!(
+                    "           {:<19} {:<17} {:<13} {}",
+                    "SCHED_CNT", "TOTAL ns", "MAX ns", PERCENTILE_HEADER
+                );
+                println!(
+                    "           {:<19} {:<17} {:<13} {}",
+                    thread.ready.count,
+                    format_number(thread.ready.total_ns),
+                    format_number(thread.ready.max_ns),
+                    format_percentiles(&thread.ready, stats.hist_step_ns)
+                );
+                if show_histogram {
+                    print_histogram(&thread.ready, stats.hist_step_ns);
+                }
+                println!();
+            }
 
-                // Print thread ready statistics
-                if !thread_ready_stats.is_empty() {
-                    println!("           [THREAD READY STATISTICS]");
+            // Print hard IRQ statistics (handlers that interrupted this thread)
+            if !thread.hardirqs.is_empty() {
+                println!("           [HARD IRQ STATISTICS]");
+                println!(
+                    "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+                    "NAME", "IRQ_NR", "COUNT", "TOTAL ns", "MAX ns", PERCENTILE_HEADER
+                );
+
+                // Sort hard IRQ stats by total time (descending) for better readability
+                let mut sorted_hardirq_stats: Vec<_> = thread.hardirqs.iter().collect();
+                sorted_hardirq_stats.sort_by_key(|(_, stat)| std::cmp::Reverse(stat.total_ns));
+
+                for (&irq, stat) in sorted_hardirq_stats {
+                    let name = get_hardirq_name(irq, &stats.hardirq_names, &interrupt_names);
                     println!(
-                        "           {:<19} {:<17} {:<13}",
-                        "SCHED_CNT", "TOTAL ns", "MAX ns"
+                        "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+                        name,
+                        irq,
+                        stat.count,
+                        format_number(stat.total_ns),
+                        format_number(stat.max_ns),
+                        format_percentiles(stat, stats.hist_step_ns)
                     );
-                    
-                    // Aggregate thread ready statistics
-                    let mut aggregated_ready_stat = ThreadReadyStat {
-                        sched_cnt: 0,
-                        total_ns: 0,
-                        max_ns: 0,
-                    };
-                    
-                    for stat in &thread_ready_stats {
-                        aggregated_ready_stat.sched_cnt += stat.sched_cnt;
-                        aggregated_ready_stat.total_ns += stat.total_ns;
-                        if stat.max_ns > aggregated_ready_stat.max_ns {
-                            aggregated_ready_stat.max_ns = stat.max_ns;
-                        }
+                    if show_histogram {
+                        print_histogram(stat, stats.hist_step_ns);
                     }
-                    
-                    println!(
-                        "           {:<19} {:<17} {:<13}",
-                        aggregated_ready_stat.sched_cnt,
-                        format_number(aggregated_ready_stat.total_ns),
-                        format_number(aggregated_ready_stat.max_ns)
-                    );
-                    println!("");
                 }
 
-                // Print IRQ statistics (soft interrupts only, no network card info)
-                if !softirq_stats.is_empty() {
-                    println!("           [SOFT IRQ STATISTICS]");
+                // Calculate total
+                let total_count: u64 = thread.hardirqs.values().map(|s| s.count).sum();
+                let total_ns: u64 = thread.hardirqs.values().map(|s| s.total_ns).sum();
+                println!(
+                    "           TOTAL: {:<32} {:<13} {:<17} {:<13}",
+                    "",
+                    "",
+                    total_count,
+                    format_number(total_ns)
+                );
+                println!();
+            }
+
+            // Print soft IRQ statistics
+            if !thread.softirqs.is_empty() {
+                println!("           [SOFT IRQ STATISTICS]");
+                println!(
+                    "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+                    "NAME", "VECT_NR", "COUNT", "TOTAL ns", "MAX ns", PERCENTILE_HEADER
+                );
+
+                // Sort softirq stats by total time (descending) for better readability
+                let mut sorted_softirq_stats: Vec<_> = thread.softirqs.iter().collect();
+                sorted_softirq_stats.sort_by_key(|(_, stat)| std::cmp::Reverse(stat.total_ns));
+
+                for (&vector, stat) in sorted_softirq_stats {
+                    let name = get_softirq_name(vector);
                     println!(
-                        "           {:<20} {:<11} {:<13} {:<17} {:<13}",
-                        "NAME", "VECT_NR", "COUNT", "TOTAL ns", "MAX ns"
+                        "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+                        name,
+                        vector,
+                        stat.count,
+                        format_number(stat.total_ns),
+                        format_number(stat.max_ns),
+                        format_percentiles(stat, stats.hist_step_ns)
                     );
-                    
-                    // Sort softirq stats by total time (descending) for better readability
-                    let mut sorted_softirq_stats: Vec<_> = softirq_stats.values().collect();
-                    sorted_softirq_stats.sort_by(|a, b| b.total_ns.cmp(&a.total_ns));
-                    
-                    for stat in sorted_softirq_stats {
-                        let name = get_softirq_name(stat.vector);
-                        println!(
-                            "           {:<20} {:<11} {:<13} {:<17} {:<13}",
-                            name,
-                            stat.vector,
-                            stat.count,
-                            format_number(stat.total_ns),
-                            format_number(stat.max_ns)
-                        );
+                    if show_histogram {
+                        print_histogram(stat, stats.hist_step_ns);
                     }
-                    
-                    // Calculate total
-                    let total_count: u32 = softirq_stats.values().map(|s| s.count).sum();
-                    let total_ns: u64 = softirq_stats.values().map(|s| s.total_ns).sum();
-                    println!(
-                        "           TOTAL: {:<32} {:<13} {:<17} {:<13}",
-                        "",
-                        "",
-                        total_count,
-                        format_number(total_ns)
-                    );
                 }
+
+                // Calculate total
+                let total_count: u64 = thread.softirqs.values().map(|s| s.count).sum();
+                let total_ns: u64 = thread.softirqs.values().map(|s| s.total_ns).sum();
+                println!(
+                    "           TOTAL: {:<32} {:<13} {:<17} {:<13}",
+                    "",
+                    "",
+                    total_count,
+                    format_number(total_ns)
+                );
             }
         }
     }
 }
 
-fn get_syscall_name(syscall_number: u32) -> String {
-    // Map syscall numbers to names
-    match syscall_number {
-        0 => "read".to_string(),
-        1 => "write".to_string(),
-        2 => "open".to_string(),
-        3 => "close".to_string(),
-        4 => "stat".to_string(),
-        5 => "fstat".to_string(),
-        6 => "lstat".to_string(),
-        7 => "poll".to_string(),
-        8 => "lseek".to_string(),
-        9 => "mmap".to_string(),
-        10 => "mprotect".to_string(),
-        11 => "munmap".to_string(),
-        12 => "brk".to_string(),
-        13 => "rt_sigaction".to_string(),
-        14 => "rt_sigprocmask".to_string(),
-        15 => "rt_sigreturn".to_string(),
-        16 => "ioctl".to_string(),
-        17 => "pread64".to_string(),
-        18 => "pwrite64".to_string(),
-        19 => "readv".to_string(),
-        20 => "writev".to_string(),
-        21 => "access".to_string(),
-        22 => "pipe".to_string(),
-        23 => "select".to_string(),
-        24 => "sched_yield".to_string(),
-        25 => "mremap".to_string(),
-        26 => "msync".to_string(),
-        27 => "mincore".to_string(),
-        28 => "madvise".to_string(),
-        29 => "shmget".to_string(),
-        30 => "shmat".to_string(),
-        31 => "shmctl".to_string(),
-        32 => "dup".to_string(),
-        33 => "dup2".to_string(),
-        34 => "pause".to_string(),
-        35 => "nanosleep".to_string(),
-        36 => "getitimer".to_string(),
-        37 => "alarm".to_string(),
-        38 => "setitimer".to_string(),
-        39 => "getpid".to_string(),
-        40 => "sendfile".to_string(),
-        41 => "socket".to_string(),
-        42 => "connect".to_string(),
-        43 => "accept".to_string(),
-        44 => "sendto".to_string(),
-        45 => "recvfrom".to_string(),
-        46 => "sendmsg".to_string(),
-        47 => "recvmsg".to_string(),
-        48 => "shutdown".to_string(),
-        49 => "bind".to_string(),
-        50 => "listen".to_string(),
-        51 => "getsockname".to_string(),
-        52 => "getpeername".to_string(),
-        53 => "socketpair".to_string(),
-        54 => "setsockopt".to_string(),
-        55 => "getsockopt".to_string(),
-        56 => "clone".to_string(),
-        57 => "fork".to_string(),
-        58 => "vfork".to_string(),
-        59 => "execve".to_string(),
-        60 => "exit".to_string(),
-        61 => "wait4".to_string(),
-        62 => "kill".to_string(),
-        63 => "uname".to_string(),
-        64 => "semget".to_string(),
-        65 => "semop".to_string(),
-        66 => "semctl".to_string(),
-        67 => "shmdt".to_string(),
-        68 => "msgget".to_string(),
-        69 => "msgsnd".to_string(),
-        70 => "msgrcv".to_string(),
-        71 => "msgctl".to_string(),
-        72 => "fcntl".to_string(),
-        73 => "flock".to_string(),
-        74 => "fsync".to_string(),
-        75 => "fdatasync".to_string(),
-        76 => "truncate".to_string(),
-        77 => "ftruncate".to_string(),
-        78 => "getdents".to_string(),
-        79 => "getcwd".to_string(),
-        80 => "chdir".to_string(),
-        81 => "fchdir".to_string(),
-        82 => "rename".to_string(),
-        83 => "mkdir".to_string(),
-        84 => "rmdir".to_string(),
-        85 => "creat".to_string(),
-        86 => "link".to_string(),
-        87 => "unlink".to_string(),
-        88 => "symlink".to_string(),
-        89 => "readlink".to_string(),
-        90 => "chmod".to_string(),
-        91 => "fchmod".to_string(),
-        92 => "chown".to_string(),
-        93 => "fchown".to_string(),
-        94 => "lchown".to_string(),
-        95 => "umask".to_string(),
-        96 => "gettimeofday".to_string(),
-        97 => "getrlimit".to_string(),
-        98 => "getrusage".to_string(),
-        99 => "sysinfo".to_string(),
-        100 => "times".to_string(),
-        101 => "ptrace".to_string(),
-        102 => "getuid".to_string(),
-        103 => "syslog".to_string(),
-        104 => "getgid".to_string(),
-        105 => "setuid".to_string(),
-        106 => "setgid".to_string(),
-        107 => "geteuid".to_string(),
-        108 => "getegid".to_string(),
-        109 => "setpgid".to_string(),
-        110 => "getppid".to_string(),
-        111 => "getpgrp".to_string(),
-        112 => "setsid".to_string(),
-        113 => "setreuid".to_string(),
-        114 => "setregid".to_string(),
-        115 => "getgroups".to_string(),
-        116 => "setgroups".to_string(),
-        117 => "setresuid".to_string(),
-        118 => "getresuid".to_string(),
-        119 => "setresgid".to_string(),
-        120 => "getresgid".to_string(),
-        121 => "getpgid".to_string(),
-        122 => "setfsuid".to_string(),
-        123 => "setfsgid".to_string(),
-        124 => "getsid".to_string(),
-        125 => "capget".to_string(),
-        126 => "capset".to_string(),
-        127 => "rt_sigpending".to_string(),
-        128 => "rt_sigtimedwait".to_string(),
-        129 => "rt_sigqueueinfo".to_string(),
-        130 => "rt_sigsuspend".to_string(),
-        131 => "sigaltstack".to_string(),
-        132 => "utime".to_string(),
-        133 => "mknod".to_string(),
-        134 => "uselib".to_string(),
-        135 => "personality".to_string(),
-        136 => "ustat".to_string(),
-        137 => "statfs".to_string(),
-        138 => "fstatfs".to_string(),
-        139 => "sysfs".to_string(),
-        140 => "getpriority".to_string(),
-        141 => "setpriority".to_string(),
-        142 => "sched_setparam".to_string(),
-        143 => "sched_getparam".to_string(),
-        144 => "sched_setscheduler".to_string(),
-        145 => "sched_getscheduler".to_string(),
-        146 => "sched_get_priority_max".to_string(),
-        147 => "sched_get_priority_min".to_string(),
-        148 => "sched_rr_get_interval".to_string(),
-        149 => "mlock".to_string(),
-        150 => "munlock".to_string(),
-        151 => "mlockall".to_string(),
-        152 => "munlockall".to_string(),
-        153 => "vhangup".to_string(),
-        154 => "modify_ldt".to_string(),
-        155 => "pivot_root".to_string(),
-        156 => "_sysctl".to_string(),
-        157 => "prctl".to_string(),
-        158 => "arch_prctl".to_string(),
-        159 => "adjtimex".to_string(),
-        160 => "setrlimit".to_string(),
-        161 => "chroot".to_string(),
-        162 => "sync".to_string(),
-        163 => "acct".to_string(),
-        164 => "settimeofday".to_string(),
-        165 => "mount".to_string(),
-        166 => "umount2".to_string(),
-        167 => "swapon".to_string(),
-        168 => "swapoff".to_string(),
-        169 => "reboot".to_string(),
-        170 => "sethostname".to_string(),
-        171 => "setdomainname".to_string(),
-        172 => "iopl".to_string(),
-        173 => "ioperm".to_string(),
-        174 => "create_module".to_string(),
-        175 => "init_module".to_string(),
-        176 => "delete_module".to_string(),
-        177 => "get_kernel_syms".to_string(),
-        178 => "query_module".to_string(),
-        179 => "quotactl".to_string(),
-        180 => "nfsservctl".to_string(),
-        181 => "getpmsg".to_string(),
-        182 => "putpmsg".to_string(),
-        183 => "afs_syscall".to_string(),
-        184 => "tuxcall".to_string(),
-        185 => "security".to_string(),
-        186 => "gettid".to_string(),
-        187 => "readahead".to_string(),
-        188 => "setxattr".to_string(),
-        189 => "lsetxattr".to_string(),
-        190 => "fsetxattr".to_string(),
-        191 => "getxattr".to_string(),
-        192 => "lgetxattr".to_string(),
-        193 => "fgetxattr".to_string(),
-        194 => "listxattr".to_string(),
-        195 => "llistxattr".to_string(),
-        196 => "flistxattr".to_string(),
-        197 => "removexattr".to_string(),
-        198 => "lremovexattr".to_string(),
-        199 => "fremovexattr".to_string(),
-        200 => "tkill".to_string(),
-        201 => "time".to_string(),
-        202 => "futex".to_string(),
-        203 => "sched_setaffinity".to_string(),
-        204 => "sched_getaffinity".to_string(),
-        205 => "set_thread_area".to_string(),
-        206 => "io_setup".to_string(),
-        207 => "io_destroy".to_string(),
-        208 => "io_getevents".to_string(),
-        209 => "io_submit".to_string(),
-        210 => "io_cancel".to_string(),
-        211 => "get_thread_area".to_string(),
-        212 => "lookup_dcookie".to_string(),
-        213 => "epoll_create".to_string(),
-        214 => "epoll_ctl_old".to_string(),
-        215 => "epoll_wait_old".to_string(),
-        216 => "remap_file_pages".to_string(),
-        217 => "getdents64".to_string(),
-        218 => "set_tid_address".to_string(),
-        219 => "restart_syscall".to_string(),
-        220 => "semtimedop".to_string(),
-        221 => "fadvise64".to_string(),
-        222 => "timer_create".to_string(),
-        223 => "timer_settime".to_string(),
-        224 => "timer_gettime".to_string(),
-        225 => "timer_getoverrun".to_string(),
-        226 => "timer_delete".to_string(),
-        227 => "clock_settime".to_string(),
-        228 => "clock_gettime".to_string(),
-        229 => "clock_getres".to_string(),
-        230 => "clock_nanosleep".to_string(),
-        231 => "exit_group".to_string(),
-        232 => "epoll_wait".to_string(),
-        233 => "epoll_ctl".to_string(),
-        234 => "tgkill".to_string(),
-        235 => "utimes".to_string(),
-        236 => "vserver".to_string(),
-        237 => "mbind".to_string(),
-        238 => "set_mempolicy".to_string(),
-        239 => "get_mempolicy".to_string(),
-        240 => "mq_open".to_string(),
-        241 => "mq_unlink".to_string(),
-        242 => "mq_timedsend".to_string(),
-        243 => "mq_timedreceive".to_string(),
-        244 => "mq_notify".to_string(),
-        245 => "mq_getsetattr".to_string(),
-        246 => "kexec_load".to_string(),
-        247 => "waitid".to_string(),
-        248 => "add_key".to_string(),
-        249 => "request_key".to_string(),
-        250 => "keyctl".to_string(),
-        251 => "ioprio_set".to_string(),
-        252 => "ioprio_get".to_string(),
-        253 => "inotify_init".to_string(),
-        254 => "inotify_add_watch".to_string(),
-        255 => "inotify_rm_watch".to_string(),
-        256 => "migrate_pages".to_string(),
-        257 => "openat".to_string(),
-        258 => "mkdirat".to_string(),
-        259 => "mknodat".to_string(),
-        260 => "fchownat".to_string(),
-        261 => "futimesat".to_string(),
-        262 => "newfstatat".to_string(),
-        263 => "unlinkat".to_string(),
-        264 => "renameat".to_string(),
-        265 => "linkat".to_string(),
-        266 => "symlinkat".to_string(),
-        267 => "readlinkat".to_string(),
-        268 => "fchmodat".to_string(),
-        269 => "faccessat".to_string(),
-        270 => "pselect6".to_string(),
-        271 => "ppoll".to_string(),
-        272 => "unshare".to_string(),
-        273 => "set_robust_list".to_string(),
-        274 => "get_robust_list".to_string(),
-        275 => "splice".to_string(),
-        276 => "tee".to_string(),
-        277 => "sync_file_range".to_string(),
-        278 => "vmsplice".to_string(),
-        279 => "move_pages".to_string(),
-        280 => "utimensat".to_string(),
-        281 => "epoll_pwait".to_string(),
-        282 => "signalfd".to_string(),
-        283 => "timerfd_create".to_string(),
-        284 => "eventfd".to_string(),
-        285 => "fallocate".to_string(),
-        286 => "timerfd_settime".to_string(),
-        287 => "timerfd_gettime".to_string(),
-        288 => "accept4".to_string(),
-        289 => "signalfd4".to_string(),
-        290 => "eventfd2".to_string(),
-        291 => "epoll_create1".to_string(),
-        292 => "dup3".to_string(),
-        293 => "pipe2".to_string(),
-        294 => "inotify_init1".to_string(),
-        295 => "preadv".to_string(),
-        296 => "pwritev".to_string(),
-        297 => "rt_tgsigqueueinfo".to_string(),
-        298 => "perf_event_open".to_string(),
-        299 => "recvmmsg".to_string(),
-        300 => "fanotify_init".to_string(),
-        301 => "fanotify_mark".to_string(),
-        302 => "prlimit64".to_string(),
-        303 => "name_to_handle_at".to_string(),
-        304 => "open_by_handle_at".to_string(),
-        305 => "clock_adjtime".to_string(),
-        306 => "syncfs".to_string(),
-        307 => "sendmmsg".to_string(),
-        308 => "setns".to_string(),
-        309 => "getcpu".to_string(),
-        310 => "process_vm_readv".to_string(),
-        311 => "process_vm_writev".to_string(),
-        312 => "kcmp".to_string(),
-        313 => "finit_module".to_string(),
-        314 => "sched_setattr".to_string(),
-        315 => "sched_getattr".to_string(),
-        316 => "renameat2".to_string(),
-        317 => "seccomp".to_string(),
-        318 => "getrandom".to_string(),
-        319 => "memfd_create".to_string(),
-        320 => "kexec_file_load".to_string(),
-        321 => "bpf".to_string(),
-        322 => "execveat".to_string(),
-        323 => "userfaultfd".to_string(),
-        324 => "membarrier".to_string(),
-        325 => "mlock2".to_string(),
-        326 => "copy_file_range".to_string(),
-        327 => "preadv2".to_string(),
-        328 => "pwritev2".to_string(),
-        329 => "pkey_mprotect".to_string(),
-        330 => "pkey_alloc".to_string(),
-        331 => "pkey_free".to_string(),
-        332 => "statx".to_string(),
-        333 => "io_pgetevents".to_string(),
-        334 => "rseq".to_string(),
-        _ => format!("syscall_{}", syscall_number),
+// Failed syscalls of a thread per errno, under its syscall table
+fn print_syscall_errors(thread: &ThreadStats, step_ns: u64, show_histogram: bool) {
+    println!("           [SYSCALL ERRORS]");
+    println!(
+        "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+        "NAME", "ERROR", "COUNT", "TOTAL ns", "MAX ns", PERCENTILE_HEADER
+    );
+
+    // Syscalls in the order of the table above, their errors by total time
+    let mut numbers: Vec<_> = thread.syscall_errors.keys().map(|&(number, _)| number).collect();
+    numbers.sort_by_key(|&number| {
+        let total_ns = thread.syscalls.get(&number).map_or(0, |stat| stat.total_ns);
+        (std::cmp::Reverse(total_ns), number)
+    });
+    numbers.dedup();
+    for number in numbers {
+        for (errno, stat) in report::syscall_errors(thread, number) {
+            println!(
+                "           {:<20} {:<11} {:<13} {:<17} {:<13} {}",
+                get_syscall_name(number),
+                errno::name(errno),
+                stat.count,
+                format_number(stat.total_ns),
+                format_number(stat.max_ns),
+                format_percentiles(stat, step_ns)
+            );
+            if show_histogram {
+                print_histogram(stat, step_ns);
+            }
+        }
+    }
+    println!();
+}
+
+// One line per slow event, oldest first, with the wall-clock time to match with other logs
+fn print_slow_events(stats: &Stats) {
+    println!();
+    println!("[SLOW EVENTS >= {} ns]", format_number(stats.slow_ns));
+    println!(
+        "{:<27} {:<10} {:<10} {:<16} {:<8} {:<20} {:<17} RET",
+        "TIME", "PID", "TID", "THREAD", "KIND", "NAME", "LATENCY ns"
+    );
+    for event in report::slow_events(stats) {
+        let ret = match event.ret {
+            Some(ret) if (-MAX_ERRNO..0).contains(&ret) => {
+                format!("{} ({})", ret, errno::name((-ret) as u32))
+            }
+            Some(ret) => ret.to_string(),
+            None => String::new(),
+        };
+        let line = format!(
+            "{:<27} {:<10} {:<10} {:<16} {:<8} {:<20} {:<17} {}",
+            event.time,
+            event.pid,
+            event.tid,
+            event.thread,
+            event.kind,
+            event.name,
+            format_number(event.latency_ns),
+            ret
+        );
+        println!("{}", line.trim_end());
+        print_stack(&event.kernel_stack, &event.user_stack);
+    }
+}
+
+// The slow events with stacks per syscall (or softirq) and stacks, most total latency first
+fn print_slow_stacks(stats: &Stats) {
+    let groups = report::slow_stacks(stats);
+    if groups.is_empty() {
+        return;
+    }
+    println!();
+    println!("[SLOW STACKS]");
+    for group in groups {
+        println!(
+            "{} {}: {} events, total {} ns, max {} ns",
+            group.kind,
+            group.name,
+            group.count,
+            format_number(group.total_ns),
+            format_number(group.max_ns)
+        );
+        print_stack(&group.kernel_stack, &group.user_stack);
+        println!();
     }
 }
 
+// Innermost frame first, the kernel stack above the user one like in the bcc tools
+fn print_stack(kernel_stack: &[String], user_stack: &[String]) {
+    for frame in kernel_stack {
+        println!("    {}", frame);
+    }
+    if !kernel_stack.is_empty() && !user_stack.is_empty() {
+        println!("    --");
+    }
+    for frame in user_stack {
+        println!("    {}", frame);
+    }
+}
+
+// How long a launched command is given to exit on the signal that stopped monitoring
+const COMMAND_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PERCENTILE_HEADER: &str = "P50 ns        P90 ns        P99 ns        P99.9 ns";
+
+fn format_percentiles(stat: &Stat, step_ns: u64) -> String {
+    format!(
+        "{:<13} {:<13} {:<13} {:<13}",
+        format_number(stat.percentile(50.0, step_ns)),
+        format_number(stat.percentile(90.0, step_ns)),
+        format_number(stat.percentile(99.0, step_ns)),
+        format_number(stat.percentile(99.9, step_ns))
+    )
+}
+
+// Same layout as the bcc tools, only the slots between the first and last non-empty ones
+fn print_histogram(stat: &Stat, step_ns: u64) {
+    const WIDTH: u64 = 40;
+
+    let Some(first) = stat.hist.iter().position(|&count| count > 0) else {
+        return;
+    };
+    let last = stat.hist.iter().rposition(|&count| count > 0).unwrap_or(first);
+    let max_count = stat.hist[first..=last].iter().copied().max().unwrap_or(1);
+
+    println!(
+        "           {:>20}    {:<20} : {:<8} distribution",
+        "nsecs", "", "count"
+    );
+    for slot in first..=last {
+        let (low, high) = hist_slot_range(slot, step_ns);
+        let count = stat.hist[slot];
+        let stars = (count * WIDTH).div_ceil(max_count) as usize;
+        println!(
+            "           {:>20} -> {:<20} : {:<8} |{:<40}|",
+            low,
+            high,
+            count,
+            "*".repeat(stars)
+        );
+    }
+    println!();
+}
+
+fn get_syscall_name(syscall_number: u32) -> String {
+    syscalls::name(syscall_number)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("syscall_{}", syscall_number))
+}
+
 fn get_softirq_name(vector: u32) -> String {
     // Map softirq vectors to names
     match vector {
@@ -620,6 +665,18 @@ fn get_softirq_name(vector: u32) -> String {
     }
 }
 
+fn get_hardirq_name(
+    irq: u32,
+    handler_names: &StdHashMap<u32, String>,
+    interrupt_names: &StdHashMap<u32, String>,
+) -> String {
+    // Prefer the handler name captured from the tracepoint, then /proc/interrupts
+    match handler_names.get(&irq).or_else(|| interrupt_names.get(&irq)) {
+        Some(name) => name.clone(),
+        None => format!("IRQ_{}", irq),
+    }
+}
+
 fn format_number(num: u64) -> String {
     // Format number with commas for thousands
     let num_str = num.to_string();
@@ -637,6 +694,185 @@ fn format_number(num: u64) -> String {
     result
 }
 
+// Time span covered by a report: the whole run, or with --interval the time since the last one
+struct Period {
+    start: chrono::DateTime<chrono::Utc>,
+    stop: chrono::DateTime<chrono::Utc>,
+    index: usize,   // Number of reports printed before this one
+    last: bool,     // Monitoring has stopped
+    interval: bool, // One of a series of --interval reports
+    dump: bool,     // Requested with SIGUSR1, monitoring goes on
+}
+
+// The lines opening a text report: when it started and what is monitored for how long
+fn print_header(
+    targets: &report::Targets,
+    start_time: &chrono::DateTime<chrono::Utc>,
+    duration: u64,
+) {
+    println!(
+        "# Start sampling @{} ({} UTC)",
+        start_time.to_rfc3339(),
+        start_time.format("%H:%M:%S")
+    );
+    let mut monitored = Vec::new();
+    if targets.all {
+        monitored.push("all processes".to_string());
+    }
+    if !targets.pids.is_empty() {
+        let pid_list: Vec<String> = targets.pids.iter().map(u32::to_string).collect();
+        monitored.push(format!(
+            "PID: {}{}",
+            pid_list.join(","),
+            if targets.pid_tree { " and descendants" } else { "" }
+        ));
+    }
+    for cgroup in &targets.cgroups {
+        monitored.push(format!("cgroup: {cgroup}"));
+    }
+    if !targets.command.is_empty() {
+        println!(
+            "# Monitoring {}, Command: {}",
+            monitored.join(", "),
+            targets.command.join(" ")
+        );
+    } else if duration == 0 {
+        println!("# Monitoring {}, until interrupted", monitored.join(", "));
+    } else {
+        println!(
+            "# Monitoring {}, Duration: {} seconds",
+            monitored.join(", "),
+            duration
+        );
+    }
+}
+
+fn print_report(
+    args: &OutputArgs,
+    targets: &report::Targets,
+    mode: Mode,
+    stats: &Stats,
+    period: &Period,
+    exit_code: Option<i32>,
+) -> anyhow::Result<()> {
+    // With --all only the worst offenders are detailed, the whole system would be far too much
+    let pids = if targets.all {
+        top_processes(stats, args.top, args.top_by)
+    } else {
+        let mut pids: Vec<_> = stats.processes.keys().copied().collect();
+        pids.sort();
+        pids
+    };
+
+    match args.format {
+        Format::Text => {
+            if period.last {
+                println!(
+                    "# Stop sampling @{} ({} UTC)",
+                    period.stop.to_rfc3339(),
+                    period.stop.format("%H:%M:%S")
+                );
+
+                let dump_time = chrono::Utc::now();
+                println!(
+                    "# Sample dump @{} ({} UTC)",
+                    dump_time.to_rfc3339(),
+                    dump_time.format("%H:%M:%S")
+                );
+            } else {
+                println!();
+                println!(
+                    "# {} @{} ({} UTC)",
+                    if period.dump { "Dump" } else { "Interval" },
+                    period.stop.to_rfc3339(),
+                    period.stop.format("%H:%M:%S")
+                );
+            }
+
+            // Print summary
+            println!("# Total events captured: {}", stats.events);
+            println!("# Events streamed: {}", stats.events_streamed);
+            println!(
+                "# Events lost: {} ({:.2}%)",
+                stats.events_lost,
+                stats.events_lost_percent()
+            );
+
+            // Print the collected events in the requested format
+            if targets.all && !stats.is_empty() {
+                print_process_summary(stats, &pids, args.top_by);
+            }
+            print_thread_statistics(stats, &pids, args.histogram, args.errors);
+            if stats.slow_ns > 0 {
+                print_slow_events(stats);
+                print_slow_stacks(stats);
+            }
+        }
+        Format::Json => {
+            let report = report::Report {
+                version: report::JSON_VERSION,
+                start_time: period.start.to_rfc3339(),
+                stop_time: period.stop.to_rfc3339(),
+                mode: match mode {
+                    Mode::Aggregate => "aggregate",
+                    Mode::Trace => "trace",
+                },
+                targets: targets.clone(),
+                events: stats.events,
+                events_streamed: stats.events_streamed,
+                events_lost: stats.events_lost,
+                events_lost_percent: stats.events_lost_percent(),
+                exit_code,
+                processes: report::processes(stats, &pids, args.histogram),
+                slow_events: report::slow_events(stats),
+                slow_stacks: report::slow_stacks(stats),
+            };
+            // One document per line when there is a stream of them
+            if period.interval {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+        }
+        Format::Csv | Format::Tsv => {
+            let separator = if args.format == Format::Csv { ',' } else { '\t' };
+            let processes = report::processes(stats, &pids, false);
+            report::write_delimited(
+                &mut std::io::stdout().lock(),
+                &processes,
+                separator,
+                &period.stop.to_rfc3339(),
+                period.index == 0,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Fold a batch of raw events from the ring buffer into the statistics (and the recording). In
+// aggregate mode only slow events are streamed, they are in the kernel's statistics already.
+fn fold_events(
+    batch: &[u8],
+    mode: Mode,
+    stats: &mut Stats,
+    recorder: &mut Option<recording::Recorder>,
+    stacks: &mut Option<Stacks>,
+) -> anyhow::Result<()> {
+    for data in batch.chunks_exact(ringbuf::EVENT_SIZE) {
+        // Parse the event and fold it into the per-thread statistics
+        if let Some(event) = parse_event(data) {
+            if let Some(recorder) = recorder {
+                recorder.write_event(data)?;
+            }
+            if mode == Mode::Trace {
+                stats.add_event(&event);
+            }
+            stats.add_slow_event(&event, stacks);
+        }
+    }
+    Ok(())
+}
+
 fn parse_event(data: &[u8]) -> Option<Event> {
     if data.len() >= std::mem::size_of::<Event>() {
         let event = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const Event) };
@@ -650,175 +886,446 @@ fn parse_event(data: &[u8]) -> Option<Event> {
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(Subcommand::Report(report_args)) = &args.subcommand {
+        check_output_args(&report_args.output)?;
+    } else {
+        check_output_args(&args.output)?;
+    }
+
+    match &args.subcommand {
+        Some(Subcommand::Serve(serve_args)) => return serve::run(serve_args).await,
+        Some(Subcommand::Report(report_args)) => return replay(report_args),
+        Some(Subcommand::Diff(diff_args)) => return diff::run(diff_args),
+        None => {}
+    }
+
+    // Recording needs every single event in userspace
+    if args.record.is_some() {
+        args.mode = Mode::Trace;
+    }
 
-    // Bump the memlock rlimit. This is needed for older kernels that don't use the
-    // new memcg based accounting, see https://lwn.net/Articles/837122/
-    let rlim = libc::rlimit {
-        rlim_cur: libc::RLIM_INFINITY,
-        rlim_max: libc::RLIM_INFINITY,
+    // Fork the command stopped, so that it is traced from its very first instruction
+    let child = if args.command.is_empty() {
+        None
+    } else {
+        Some(Child::spawn_stopped(&args.command)?)
     };
-    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
-    if ret != 0 {
-        debug!("remove limit on locked memory failed, ret is: {ret}");
-    }
-
-    // This will include your eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/kernel-delay"
-    )))?;
-    match aya_log::EbpfLogger::init(&mut ebpf) {
-        Err(e) => {
-            // This can happen if you remove all log statements from your eBPF program.
-            warn!("failed to initialize eBPF logger: {e}");
+    let mut tracer = Tracer::start(
+        &args.trace,
+        child.as_ref().map(|child| child.pid),
+        args.mode,
+        args.ringbuf_size,
+        args.output.slow.unwrap_or(0),
+        args.stacks,
+    )?;
+
+    // Get reference to the ring buffer, read from now on by a task of its own
+    let ring_buf_map = tracer.ebpf.take_map("RING_BUF").unwrap();
+    let mut event_reader = EventReader::spawn(RingBuf::try_from(ring_buf_map)?)?;
+    let mut stacks = if args.stacks {
+        let stack_map = tracer.ebpf.take_map("STACKS").unwrap();
+        Some(Stacks::new(StackTraceMap::try_from(stack_map)?))
+    } else {
+        None
+    };
+
+    // Print header
+    let start_time = chrono::Utc::now();
+    let targets = report::Targets {
+        all: args.trace.all,
+        pids: tracer.pids.clone(),
+        pid_tree: args.trace.pid_tree,
+        cgroups: tracer
+            .cgroup_dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect(),
+        command: args.command.clone(),
+    };
+    if args.output.format == Format::Text {
+        print_header(&targets, &start_time, args.duration);
+    }
+
+    let mut recorder = match &args.record {
+        Some(path) => {
+            let header = recording::Header::new(
+                start_time.to_rfc3339(),
+                args.duration,
+                args.trace.hist_linear.unwrap_or(0),
+                targets.clone(),
+            );
+            Some(recording::Recorder::create(path, &header)?)
         }
-        Ok(logger) => {
-            let mut logger =
-                tokio::io::unix::AsyncFd::with_interest(logger, tokio::io::Interest::READABLE)?;
-            tokio::task::spawn(async move {
-                loop {
-                    let mut guard = logger.readable_mut().await.unwrap();
-                    guard.get_inner_mut().flush();
-                    guard.clear_ready();
+        None => None,
+    };
+
+    // Collect events for a period of time
+    let start_instant = std::time::Instant::now();
+    let mut stats = Stats {
+        hist_step_ns: args.trace.hist_linear.unwrap_or(0),
+        slow_ns: args.output.slow.unwrap_or(0),
+        clock_offset_ns: clock_offset_ns(),
+        ..Default::default()
+    };
+
+    // Everything is attached, let the command go
+    if let Some(child) = &child {
+        child.resume()?;
+    }
+
+    // Ctrl-C and SIGTERM stop monitoring early, SIGUSR1 asks for a report of what was measured
+    // so far
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let mut stop_signal = None;
+    let mut dump_requested = false;
+
+    // A launched command is monitored until it exits, anything else for the given duration
+    let mut command_exit_code = None;
+    let mut period_start = start_time;
+    let mut reports = 0;
+    let mut next_report = args
+        .interval
+        .map(|secs| start_instant + Duration::from_secs(secs));
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        // Fold in events as they arrive (trace mode, or slow events), check on the rest every tick
+        tokio::select! {
+            Some(batch) = event_reader.batches.recv() => {
+                fold_events(&batch, args.mode, &mut stats, &mut recorder, &mut stacks)?;
+                continue;
+            }
+            _ = tick.tick() => {}
+            _ = sigint.recv() => stop_signal = Some(libc::SIGINT),
+            _ = sigterm.recv() => stop_signal = Some(libc::SIGTERM),
+            _ = sigusr1.recv() => dump_requested = true,
+        }
+
+        if let Some(signal) = stop_signal {
+            // Pass it on. The command shares our process group, so a Ctrl-C on the terminal
+            // already reached it when it is in the foreground: sending it again would interrupt
+            // commands that handle the first one (e.g. shells or a graceful shutdown).
+            if let Some(child) = &child {
+                let from_terminal = signal == libc::SIGINT && child.in_terminal_foreground();
+                if !from_terminal {
+                    child.signal(signal);
                 }
-            });
+            }
+            break;
         }
-    }
 
-    // Set the target PID in the eBPF map (using key 0 to store the PID)
-    let mut pid_map = AyaHashMap::try_from(ebpf.take_map("TARGET_PID").unwrap())?;
-    pid_map.insert(0u64, args.pid as u64, 0)?;
+        tracer.rescan_cgroups();
+        tracer.forget_exited_pids();
 
-    // Attach to syscall tracepoints (only attach to ones that exist)
-    let program: &mut TracePoint = ebpf.program_mut("syscall_enter").unwrap().try_into()?;
-    program.load()?;
-    
-    // Try to attach to common syscall tracepoints that exist on this system
-    let syscalls_to_attach = vec![
-        ("syscalls", "sys_enter_read"),
-        ("syscalls", "sys_enter_write"),
-        ("syscalls", "sys_enter_openat"),
-        ("syscalls", "sys_enter_close"),
-        ("syscalls", "sys_enter_poll"),
-        ("syscalls", "sys_enter_lseek"),
-        ("syscalls", "sys_enter_mmap"),
-        ("syscalls", "sys_enter_mprotect"),
-        ("syscalls", "sys_enter_munmap"),
-    ];
-    
-    for (category, name) in syscalls_to_attach {
-        match program.attach(category, name) {
-            Ok(_) => debug!("Successfully attached to {}:{}", category, name),
-            Err(e) => debug!("Failed to attach to {}:{}: {}", category, name, e),
+        // Report everything since the start of the period without disturbing it
+        if dump_requested {
+            let mut snapshot = stats.clone();
+            snapshot.read_maps(&mut tracer.ebpf, false)?;
+            let period = Period {
+                start: period_start,
+                stop: chrono::Utc::now(),
+                index: reports,
+                last: false,
+                interval: args.interval.is_some(),
+                dump: true,
+            };
+            print_report(&args.output, &targets, args.mode, &snapshot, &period, None)?;
+            reports += 1;
+            dump_requested = false;
+        }
+
+        match &child {
+            Some(child) => {
+                if let Some(code) = child.try_wait()? {
+                    command_exit_code = Some(code);
+                    break;
+                }
+            }
+            None => {
+                if args.duration > 0 && start_instant.elapsed().as_secs() >= args.duration {
+                    break;
+                }
+            }
+        }
+
+        // Report the interval that just ended and start the next one from scratch
+        if next_report.is_some_and(|at| std::time::Instant::now() >= at) {
+            let now = chrono::Utc::now();
+            stats.read_maps(&mut tracer.ebpf, true)?;
+            let period = Period {
+                start: period_start,
+                stop: now,
+                index: reports,
+                last: false,
+                interval: true,
+                dump: false,
+            };
+            print_report(&args.output, &targets, args.mode, &stats, &period, None)?;
+            stats.reset();
+            reports += 1;
+            period_start = now;
+            next_report = next_report
+                .zip(args.interval)
+                .map(|(at, secs)| at + Duration::from_secs(secs));
         }
     }
 
-    let program: &mut TracePoint = ebpf.program_mut("syscall_exit").unwrap().try_into()?;
-    program.load()?;
-    
-    // new kernel syscall is openat , not open , check "mount | grep tracefs"
-    // kernel 6.1.0-38-arm64, 在旧的内核中叫 sys_enter_open 新的内核里 sys_enter_openat.
-    // ls /sys/kernel/tracing/events/syscalls| grep open
-    // sys_enter_fsopen
-    // sys_enter_mq_open
-    // sys_enter_openat
-    // sys_enter_openat2
-    // ...
-
-    let syscalls_to_attach = vec![
-        ("syscalls", "sys_exit_read"),
-        ("syscalls", "sys_exit_write"),
-        ("syscalls", "sys_exit_openat"),
-        ("syscalls", "sys_exit_close"),
-        ("syscalls", "sys_exit_poll"),
-        ("syscalls", "sys_exit_lseek"),
-        ("syscalls", "sys_exit_mmap"),
-        ("syscalls", "sys_exit_mprotect"),
-        ("syscalls", "sys_exit_munmap"),
-    ];
-    
-    for (category, name) in syscalls_to_attach {
-        match program.attach(category, name) {
-            Ok(_) => debug!("Successfully attached to {}:{}", category, name),
-            Err(e) => debug!("Failed to attach to {}:{}: {}", category, name, e),
+    // Keep monitoring a command stopped by the signal while it exits, to report its real exit
+    // code. Another signal stops waiting.
+    if let (Some(child), Some(_), None) = (&child, stop_signal, command_exit_code) {
+        let deadline = tokio::time::Instant::now() + COMMAND_EXIT_TIMEOUT;
+        loop {
+            tokio::select! {
+                Some(batch) = event_reader.batches.recv() => {
+                    fold_events(&batch, args.mode, &mut stats, &mut recorder, &mut stacks)?;
+                    continue;
+                }
+                _ = tick.tick() => {}
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = sigint.recv() => break,
+                _ = sigterm.recv() => break,
+            }
+            if let Some(code) = child.try_wait()? {
+                command_exit_code = Some(code);
+                break;
+            }
         }
     }
 
-    // Attach to softirq tracepoints
-    let program: &mut TracePoint = ebpf.program_mut("softirq_entry").unwrap().try_into()?;
-    program.load()?;
-    match program.attach("irq", "softirq_entry") {
-        Ok(_) => debug!("Successfully attached to irq:softirq_entry"),
-        Err(e) => debug!("Failed to attach to irq:softirq_entry: {}", e),
+    // Take the events still in flight, up to the end of monitoring
+    let stop_time = chrono::Utc::now();
+    event_reader.stop();
+    while let Some(batch) = event_reader.batches.recv().await {
+        fold_events(&batch, args.mode, &mut stats, &mut recorder, &mut stacks)?;
     }
 
-    let program: &mut TracePoint = ebpf.program_mut("softirq_exit").unwrap().try_into()?;
-    program.load()?;
-    match program.attach("irq", "softirq_exit") {
-        Ok(_) => debug!("Successfully attached to irq:softirq_exit"),
-        Err(e) => debug!("Failed to attach to irq:softirq_exit: {}", e),
+    // Pick up what was aggregated in the kernel
+    stats.read_maps(&mut tracer.ebpf, false)?;
+
+    if let Some(recorder) = recorder {
+        recorder.finish(&recording::Footer {
+            stop_time: stop_time.to_rfc3339(),
+            exit_code: command_exit_code,
+            events_lost: stats.events_lost_total(),
+        })?;
     }
 
-    // Get reference to the ring buffer
-    let ring_buf_map = ebpf.take_map("RING_BUF").unwrap();
-    let mut ring_buf = RingBuf::try_from(ring_buf_map)?;
+    let period = Period {
+        start: period_start,
+        stop: stop_time,
+        index: reports,
+        last: true,
+        interval: args.interval.is_some(),
+        dump: false,
+    };
+    print_report(&args.output, &targets, args.mode, &stats, &period, command_exit_code)?;
+    if stats.events_lost_total() > 0 {
+        warn!(
+            "{} events were lost because the ring buffer was full, the report is incomplete. Try \
+             a larger --ringbuf-size (or --mode aggregate).",
+            stats.events_lost_total()
+        );
+    }
+    if stats.forks_missed > 0 {
+        warn!(
+            "{} processes forked by the targets were not monitored, too many target processes",
+            stats.forks_missed
+        );
+    }
 
-    // Print header
-    let start_time = chrono::Utc::now();
-    println!(
-        "# Start sampling @{} ({} UTC)",
-        start_time.to_rfc3339(),
-        start_time.format("%H:%M:%S")
-    );
-    println!("# Monitoring PID: {}, Duration: {} seconds", args.pid, args.duration);
+    // Detach the programs before exiting, process::exit() doesn't run destructors
+    drop(tracer);
 
-    // Collect events for a period of time
-    let start_instant = std::time::Instant::now();
-    let mut thread_events: StdHashMap<u32, Vec<Event>> = StdHashMap::new();
-    let mut event_count = 0;
-
-    while start_instant.elapsed().as_secs() < args.duration {
-        // Try to read events from the ring buffer
-        while let Some(item) = ring_buf.next() {
-            // Parse the event
-            if let Some(event) = parse_event(&item) {
-                event_count += 1;
-                // Group events by thread ID
-                thread_events
-                    .entry(event.tid)
-                    .or_insert_with(Vec::new)
-                    .push(event);
-            }
+    // The command's exit code, shell style when it was still running after the signal
+    let exit_code = match (&child, command_exit_code, stop_signal) {
+        (Some(_), Some(code), _) => code,
+        (Some(_), None, Some(signal)) => 128 + signal,
+        _ => 0,
+    };
+    info!("Exiting...");
+    std::process::exit(exit_code);
+}
+
+// `report` subcommand: run a recording through the same aggregation and printers as a live run
+fn replay(args: &ReportArgs) -> anyhow::Result<()> {
+    let mut reader = recording::Reader::open(&args.file)?;
+    procfs::go_offline();
+    syscalls::set_arch(&reader.header.arch);
+    let header = &reader.header;
+    let start_time = parse_time(&header.start_time)?;
+    let targets = header.targets.clone();
+    if args.output.format == Format::Text {
+        println!(
+            "# Recorded on {} ({} {})",
+            header.hostname, header.kernel, header.arch
+        );
+        print_header(&targets, &start_time, header.duration);
+    }
+
+    let mut stats = reader.read_stats(args.output.slow.unwrap_or(0))?;
+
+    // Without a footer the stop time is unknown
+    let (stop_time, exit_code) = match &reader.footer {
+        Some(footer) => {
+            stats.events_lost = footer.events_lost;
+            (parse_time(&footer.stop_time)?, footer.exit_code)
         }
+        None => {
+            warn!("{} is incomplete, kernel-delay did not stop normally", args.file.display());
+            (start_time, None)
+        }
+    };
+    let period = Period {
+        start: start_time,
+        stop: stop_time,
+        index: 0,
+        last: true,
+        interval: false,
+        dump: false,
+    };
+    print_report(&args.output, &targets, Mode::Trace, &stats, &period, exit_code)
+}
 
-        // Small delay to avoid busy looping
-        thread::sleep(Duration::from_millis(100));
+// `--ringbuf-size`: bytes, or KiB/MiB/GiB with a K/M/G suffix
+fn parse_ringbuf_size(size: &str) -> Result<u32, String> {
+    let (digits, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("unknown unit {unit:?}, expected K, M or G")),
+    };
+    let bytes = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_shl(shift).filter(|&bytes| bytes >> shift == n))
+        .and_then(|bytes| u32::try_from(bytes).ok())
+        .ok_or_else(|| format!("invalid size {size:?}"))?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    if !bytes.is_power_of_two() || bytes < page_size {
+        return Err(format!(
+            "must be a power of 2 multiple of the page size ({page_size} bytes)"
+        ));
     }
+    Ok(bytes)
+}
 
-    let stop_time = chrono::Utc::now();
-    println!(
-        "# Stop sampling @{} ({} UTC)",
-        stop_time.to_rfc3339(),
-        stop_time.format("%H:%M:%S")
-    );
+// Options that can't be combined, beyond what clap checks
+fn check_output_args(args: &OutputArgs) -> anyhow::Result<()> {
+    if args.slow.is_some() && matches!(args.format, Format::Csv | Format::Tsv) {
+        anyhow::bail!("--slow is not available with --format csv/tsv");
+    }
+    Ok(())
+}
 
-    let dump_time = chrono::Utc::now();
-    println!(
-        "# Sample dump @{} ({} UTC)",
-        dump_time.to_rfc3339(),
-        dump_time.format("%H:%M:%S")
-    );
+// `--slow`: a number with a unit, ns, us, ms or s (e.g. 3ms or 1.5ms)
+fn parse_duration_ns(duration: &str) -> Result<u64, String> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => duration.split_at(i),
+        None => return Err(format!("missing unit in {duration:?}, expected ns, us, ms or s")),
+    };
+    let scale = match unit {
+        "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        _ => return Err(format!("unknown unit {unit:?}, expected ns, us, ms or s")),
+    };
+    let ns = number
+        .parse::<f64>()
+        .map(|number| (number * scale).round())
+        .ok()
+        .filter(|&ns| ns >= 1.0 && ns < u64::MAX as f64)
+        .ok_or_else(|| format!("invalid duration {duration:?}"))?;
+    Ok(ns as u64)
+}
 
-    // Print summary
-    println!("# Total events captured: {}", event_count);
-    
-    // Print the collected events in the requested format
-    print_thread_statistics(&thread_events, args.pid);
+// CLOCK_REALTIME minus CLOCK_MONOTONIC: bpf_ktime_get_ns() timestamps plus this are wall-clock
+// times, as long as the clock isn't set
+fn clock_offset_ns() -> i64 {
+    let now = |clock| {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(clock, &mut time) };
+        time.tv_sec * 1_000_000_000 + time.tv_nsec
+    };
+    now(libc::CLOCK_REALTIME) - now(libc::CLOCK_MONOTONIC)
+}
 
-    info!("Exiting...");
-    std::process::exit(0);
+fn parse_time(time: &str) -> anyhow::Result<chrono::DateTime<chrono::Utc>> {
+    Ok(chrono::DateTime::parse_from_rfc3339(time)?.with_timezone(&chrono::Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ringbuf_size_suffixes() {
+        assert_eq!(parse_ringbuf_size("65536"), Ok(64 << 10));
+        assert_eq!(parse_ringbuf_size("256K"), Ok(256 << 10));
+        assert_eq!(parse_ringbuf_size("1M"), Ok(1 << 20));
+        assert_eq!(parse_ringbuf_size("16m"), Ok(16 << 20));
+        assert_eq!(parse_ringbuf_size("1G"), Ok(1 << 30));
+        assert!(parse_ringbuf_size("1T").is_err());
+        assert!(parse_ringbuf_size("M").is_err());
+        assert!(parse_ringbuf_size("").is_err());
+    }
+
+    #[test]
+    fn ringbuf_size_is_a_power_of_two_page_multiple() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        assert_eq!(parse_ringbuf_size(&page_size.to_string()), Ok(page_size));
+        assert!(parse_ringbuf_size(&(page_size / 2).to_string()).is_err());
+        assert!(parse_ringbuf_size("3M").is_err());
+        assert!(parse_ringbuf_size("0").is_err());
+    }
+
+    #[test]
+    fn ringbuf_size_fits_u32() {
+        assert_eq!(parse_ringbuf_size("2G"), Ok(1 << 31));
+        assert!(parse_ringbuf_size("4G").is_err());
+        assert!(parse_ringbuf_size("17179869184K").is_err());
+        assert!(parse_ringbuf_size("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration_ns("250ns"), Ok(250));
+        assert_eq!(parse_duration_ns("3us"), Ok(3_000));
+        assert_eq!(parse_duration_ns("1.5ms"), Ok(1_500_000));
+        assert_eq!(parse_duration_ns("2s"), Ok(2_000_000_000));
+        assert_eq!(parse_duration_ns("0.0015s"), Ok(1_500_000));
+        assert!(parse_duration_ns("3m").is_err());
+        assert!(parse_duration_ns("3 ms").is_err());
+        assert!(parse_duration_ns("1e3ms").is_err());
+    }
+
+    #[test]
+    fn duration_needs_a_number_and_a_unit() {
+        assert!(parse_duration_ns("").is_err());
+        assert!(parse_duration_ns("3").is_err());
+        assert!(parse_duration_ns("ms").is_err());
+        assert!(parse_duration_ns("1.2.3ms").is_err());
+    }
+
+    #[test]
+    fn duration_range() {
+        // Below 1ns once rounded, or beyond u64
+        assert!(parse_duration_ns("0ms").is_err());
+        assert!(parse_duration_ns("0.4ns").is_err());
+        assert_eq!(parse_duration_ns("0.5ns"), Ok(1));
+        assert_eq!(parse_duration_ns("100000s"), Ok(100_000_000_000_000));
+        assert!(parse_duration_ns("18446744073s").is_ok());
+        assert!(parse_duration_ns("18446744074s").is_err());
+        assert!(parse_duration_ns("99999999999999999999999ns").is_err());
+    }
 }
@@ -0,0 +1,277 @@
+use std::fmt::Write as _;
+
+use kernel_delay_common::{HIST_SLOTS, hist_slot_range};
+
+use crate::{
+    errno, get_hardirq_name, get_softirq_name, get_syscall_name, get_thread_name, procfs,
+    stats::{Stat, Stats},
+};
+
+// Histogram buckets end at the first slot starting above this, longer latencies only count in
+// the +Inf bucket. Keeps the log2 slots at a sane number of series.
+const MAX_BUCKET_NS: u64 = 60_000_000_000;
+
+// One metric of the exposition, its samples are collected while walking the threads
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: String,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            help,
+            samples: String::new(),
+        }
+    }
+
+    // Cumulative `_bucket`s from the histogram slots, `_sum` and `_count`
+    fn histogram(&mut self, labels: &str, stat: &Stat, step_ns: u64) {
+        if stat.count == 0 {
+            return;
+        }
+        let name = self.name;
+        let out = &mut self.samples;
+        let mut cumulative = 0;
+        for slot in 0..HIST_SLOTS - 1 {
+            let (low_ns, high_ns) = hist_slot_range(slot, step_ns);
+            if low_ns > MAX_BUCKET_NS {
+                break;
+            }
+            cumulative += stat.hist.get(slot).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"{}\"}} {cumulative}",
+                seconds(high_ns)
+            );
+        }
+        // Counted from the histogram, so that the buckets never exceed the total even if the
+        // in-kernel maps were read in the middle of an update
+        let count: u64 = stat.hist.iter().sum();
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", seconds(stat.total_ns));
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+
+    fn counter(&mut self, labels: &str, value: impl std::fmt::Display) {
+        let _ = writeln!(self.samples, "{}{{{labels}}} {value}", self.name);
+    }
+
+    fn write(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        out.push_str(&self.samples);
+    }
+}
+
+/// Everything aggregated so far, in the Prometheus text exposition format (version 0.0.4).
+/// Latencies are in seconds, every thread is a set of series labelled with `pid`, `tid` and its
+/// name `comm`.
+pub fn render(stats: &Stats) -> String {
+    let interrupt_names = procfs::interrupt_names();
+    let step_ns = stats.hist_step_ns;
+    let mut syscalls = Family::new(
+        "kernel_delay_syscall_seconds",
+        "histogram",
+        "Time from syscall entry to exit.",
+    );
+    let mut syscall_errors = Family::new(
+        "kernel_delay_syscall_errors_total",
+        "counter",
+        "Syscalls that failed, per errno.",
+    );
+    let mut runqueue = Family::new(
+        "kernel_delay_runqueue_seconds",
+        "histogram",
+        "Time runnable threads waited on the run queue for a CPU.",
+    );
+    let mut oncpu = Family::new(
+        "kernel_delay_oncpu_seconds_total",
+        "counter",
+        "Time threads spent running on a CPU.",
+    );
+    let mut hardirqs = Family::new(
+        "kernel_delay_hardirq_seconds",
+        "histogram",
+        "Time hard IRQ handlers interrupted threads.",
+    );
+    let mut softirqs = Family::new(
+        "kernel_delay_softirq_seconds",
+        "histogram",
+        "Time soft IRQs interrupted threads.",
+    );
+
+    let mut pids: Vec<_> = stats.processes.keys().copied().collect();
+    pids.sort();
+    for pid in pids {
+        let process = &stats.processes[&pid];
+        let mut tids: Vec<_> = process.threads.keys().copied().collect();
+        tids.sort();
+        for tid in tids {
+            let thread = &process.threads[&tid];
+            let labels = format!(
+                "pid=\"{pid}\",tid=\"{tid}\",comm=\"{}\"",
+                escape(&get_thread_name(&thread.thread_name, tid, pid))
+            );
+
+            for (&number, stat) in sorted(&thread.syscalls) {
+                let syscall = escape(&get_syscall_name(number));
+                syscalls.histogram(&format!("{labels},syscall=\"{syscall}\""), stat, step_ns);
+            }
+            let mut errors: Vec<_> = thread.syscall_errors.iter().collect();
+            errors.sort_by_key(|&(&key, _)| key);
+            for (&(number, errno), stat) in errors {
+                let labels = format!(
+                    "{labels},syscall=\"{}\",errno=\"{}\"",
+                    escape(&get_syscall_name(number)),
+                    errno::name(errno)
+                );
+                syscall_errors.counter(&labels, stat.count);
+            }
+            runqueue.histogram(&labels, &thread.ready, step_ns);
+            if thread.run.count > 0 {
+                oncpu.counter(&labels, seconds(thread.run.total_ns));
+            }
+            for (&irq, stat) in sorted(&thread.hardirqs) {
+                let name = get_hardirq_name(irq, &stats.hardirq_names, &interrupt_names);
+                let labels = format!("{labels},irq=\"{irq}\",name=\"{}\"", escape(&name));
+                hardirqs.histogram(&labels, stat, step_ns);
+            }
+            for (&vector, stat) in sorted(&thread.softirqs) {
+                let vector = escape(&get_softirq_name(vector));
+                softirqs.histogram(&format!("{labels},vector=\"{vector}\""), stat, step_ns);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    let mut events = Family::new(
+        "kernel_delay_events_total",
+        "counter",
+        "Measurements taken since kernel-delay started.",
+    );
+    let _ = writeln!(events.samples, "{} {}", events.name, stats.events);
+    for family in [
+        &events,
+        &syscalls,
+        &syscall_errors,
+        &runqueue,
+        &oncpu,
+        &hardirqs,
+        &softirqs,
+    ] {
+        family.write(&mut out);
+    }
+    out
+}
+
+fn sorted(stats: &std::collections::HashMap<u32, Stat>) -> Vec<(&u32, &Stat)> {
+    let mut rows: Vec<_> = stats.iter().collect();
+    rows.sort_by_key(|&(&number, _)| number);
+    rows
+}
+
+fn seconds(ns: u64) -> f64 {
+    ns as f64 / 1e9
+}
+
+// Label values may contain anything but backslashes, double quotes and line feeds, which are
+// escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{ProcessStats, ThreadStats};
+
+    // One thread with the given name that waited on the run queue, `(slot, count)` per slot
+    fn with_runqueue(comm: &str, slots: &[(usize, u64)], total_ns: u64) -> Stats {
+        let mut thread = ThreadStats::default();
+        thread.thread_name[..comm.len()].copy_from_slice(comm.as_bytes());
+        let count = slots.iter().map(|&(_, count)| count).sum();
+        thread.ready.add(count, total_ns, 1, total_ns);
+        for &(slot, count) in slots {
+            thread.ready.add_hist(slot, count);
+        }
+        let mut process = ProcessStats::default();
+        process.threads.insert(2, thread);
+        let mut stats = Stats::default();
+        stats.processes.insert(1, process);
+        stats
+    }
+
+    // The `le` and value of every bucket of a family, in order
+    fn buckets(out: &str, name: &str) -> Vec<(String, u64)> {
+        let prefix = format!("{name}_bucket{{");
+        out.lines()
+            .filter(|line| line.starts_with(&prefix))
+            .map(|line| {
+                let le = line
+                    .split("le=\"")
+                    .nth(1)
+                    .unwrap()
+                    .split('"')
+                    .next()
+                    .unwrap();
+                let value = line.rsplit(' ').next().unwrap().parse().unwrap();
+                (le.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn sample(out: &str, prefix: &str) -> String {
+        let line = out.lines().find(|line| line.starts_with(prefix)).unwrap();
+        line.rsplit(' ').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+
+        let out = render(&with_runqueue("we\"ird\\", &[(1, 1)], 1));
+        assert!(out.contains("comm=\"we\\\"ird\\\\\""), "{out}");
+    }
+
+    #[test]
+    fn buckets_are_cumulative_and_end_with_inf() {
+        let out = render(&with_runqueue("worker", &[(0, 2), (3, 5), (10, 1)], 1_000));
+        let buckets = buckets(&out, "kernel_delay_runqueue_seconds");
+        let (last, finite) = buckets.split_last().unwrap();
+        assert_eq!(last, &("+Inf".to_string(), 8));
+        assert_eq!(finite[0].1, 2);
+        assert_eq!(finite[3].1, 7);
+        assert_eq!(finite.last().unwrap().1, 8);
+        for pair in finite.windows(2) {
+            let low: f64 = pair[0].0.parse().unwrap();
+            let high: f64 = pair[1].0.parse().unwrap();
+            assert!(low < high, "{pair:?}");
+            assert!(pair[0].1 <= pair[1].1, "{pair:?}");
+        }
+        // Up to the first slot starting above a minute
+        let top: f64 = finite.last().unwrap().0.parse().unwrap();
+        assert!(top > 60.0 && top < 240.0, "{top}");
+    }
+
+    #[test]
+    fn count_and_sum_match_the_buckets() {
+        let out = render(&with_runqueue("worker", &[(1, 3), (63, 1)], 2_500_000_000));
+        let buckets = buckets(&out, "kernel_delay_runqueue_seconds");
+        let inf = buckets.last().unwrap();
+        assert_eq!(inf.0, "+Inf");
+        // The slot above the last bucket only shows in +Inf
+        assert_eq!(buckets[buckets.len() - 2].1, 3);
+        assert_eq!(inf.1, 4);
+        assert_eq!(sample(&out, "kernel_delay_runqueue_seconds_count{"), "4");
+        assert_eq!(sample(&out, "kernel_delay_runqueue_seconds_sum{"), "2.5");
+    }
+}
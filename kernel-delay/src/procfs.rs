@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Set when reporting on a recording, /proc then describes another host (or another time)
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Stop looking up names in /proc, the report will only use the names captured in the events.
+pub fn go_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+fn offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// TIDs of all threads currently belonging to `pid`.
+pub fn thread_ids(pid: u32) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir(format!("/proc/{pid}/task")) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect()
+}
+
+/// Whether process `pid` still exists, i.e. has a thread left (or is a zombie).
+pub fn process_exists(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Whether thread `tid` of process `pid` is still alive.
+pub fn thread_exists(pid: u32, tid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}/task/{tid}")).exists()
+}
+
+/// PIDs of all processes descending from `pids` (children, grandchildren, ...), found through
+/// the parent PID in every `/proc/<pid>/stat`.
+pub fn descendants(pids: &[u32]) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        if let Some(ppid) = parent_pid(pid) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut queue = pids.to_vec();
+    while let Some(pid) = queue.pop() {
+        for &child in children.get(&pid).into_iter().flatten() {
+            if !found.contains(&child) && !pids.contains(&child) {
+                found.push(child);
+                queue.push(child);
+            }
+        }
+    }
+    found
+}
+
+// `/proc/<pid>/stat` is `pid (comm) state ppid ...`, where comm may itself contain spaces and
+// parentheses
+fn parent_pid(pid: u32) -> Option<u32> {
+    let text = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (_, rest) = text.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Name of a thread from `/proc/<pid>/task/<tid>/comm`.
+pub fn thread_name(pid: u32, tid: u32) -> Option<String> {
+    if offline() {
+        return None;
+    }
+    let name = fs::read_to_string(format!("/proc/{pid}/task/{tid}/comm")).ok()?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Name of a process from `/proc/<pid>/comm`.
+pub fn process_name(pid: u32) -> Option<String> {
+    if offline() {
+        return None;
+    }
+    let name = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Release of the running kernel (`uname -r`).
+pub fn kernel_release() -> Option<String> {
+    read_trimmed("/proc/sys/kernel/osrelease")
+}
+
+pub fn hostname() -> Option<String> {
+    read_trimmed("/proc/sys/kernel/hostname")
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    let text = fs::read_to_string(path).ok()?;
+    Some(text.trim().to_string())
+}
+
+/// IRQ line number -> device name, from `/proc/interrupts`.
+///
+/// Lines look like `  24:    0    12  IR-PCI-MSI 1048576-edge      nvme0q0`; the last column is
+/// the name of the handler (or a comma separated list when the line is shared).
+pub fn interrupt_names() -> HashMap<u32, String> {
+    if offline() {
+        return HashMap::new();
+    }
+    let Ok(text) = fs::read_to_string("/proc/interrupts") else {
+        return HashMap::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let (irq, rest) = line.trim_start().split_once(':')?;
+            let irq = irq.parse().ok()?;
+            let name = rest.split_whitespace().last()?;
+            Some((irq, name.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,320 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::Path,
+};
+
+use anyhow::{Context as _, bail};
+use kernel_delay_common::{EVENT_VERSION, Event};
+use serde::{Deserialize, Serialize};
+
+use crate::{clock_offset_ns, parse_event, procfs, report::Targets, stats::Stats};
+
+// A recording is the magic, the format version and the length of the JSON header (both u32 little
+// endian), the header, and then frames that start with a tag byte: a raw `Event` or the JSON
+// footer, which is the last frame of a complete recording.
+const MAGIC: &[u8; 8] = b"KDELAY\0\0";
+const FORMAT_VERSION: u32 = 1;
+const TAG_EVENT: u8 = 1;
+const TAG_FOOTER: u8 = 2;
+
+/// What was recorded, where and how, written at the start of a recording.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    /// `EVENT_VERSION` and size of the `Event` layout of the frames
+    pub event_version: u32,
+    pub event_size: u32,
+    pub kernel: String,
+    pub arch: String,
+    pub hostname: String,
+    pub start_time: String,
+    /// `--duration`, 0 when running until interrupted
+    pub duration: u64,
+    /// `Config::hist_step_ns` of the run
+    pub hist_step_ns: u64,
+    /// CLOCK_REALTIME minus CLOCK_MONOTONIC, to date the events with `--slow`
+    #[serde(default)]
+    pub clock_offset_ns: i64,
+    pub targets: Targets,
+}
+
+impl Header {
+    /// Header describing this host.
+    pub fn new(start_time: String, duration: u64, hist_step_ns: u64, targets: Targets) -> Self {
+        Self {
+            event_version: EVENT_VERSION,
+            event_size: size_of::<Event>() as u32,
+            kernel: procfs::kernel_release().unwrap_or_default(),
+            arch: std::env::consts::ARCH.to_string(),
+            hostname: procfs::hostname().unwrap_or_default(),
+            start_time,
+            duration,
+            hist_step_ns,
+            clock_offset_ns: clock_offset_ns(),
+            targets,
+        }
+    }
+}
+
+/// How the recording ended, written when it is closed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Footer {
+    pub stop_time: String,
+    pub exit_code: Option<i32>,
+    /// Events dropped because the ring buffer was full, they are missing from the recording
+    #[serde(default)]
+    pub events_lost: u64,
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, header: &Header) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let header = serde_json::to_vec(header)?;
+        out.write_all(MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u32).to_le_bytes())?;
+        out.write_all(&header)?;
+        Ok(Self { out })
+    }
+
+    /// Append an event as read from the ring buffer.
+    pub fn write_event(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(&[TAG_EVENT])?;
+        self.out.write_all(&data[..size_of::<Event>()])
+    }
+
+    pub fn finish(mut self, footer: &Footer) -> anyhow::Result<()> {
+        let footer = serde_json::to_vec(footer)?;
+        self.out.write_all(&[TAG_FOOTER])?;
+        self.out.write_all(&(footer.len() as u32).to_le_bytes())?;
+        self.out.write_all(&footer)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Whether `path` starts like a recording.
+pub fn is_recording(path: &Path) -> bool {
+    let mut magic = [0; 8];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+/// Reads a recording back, event by event.
+pub struct Reader {
+    pub header: Header,
+    /// Set once all events have been read, None when the recording was cut short (e.g.
+    /// kernel-delay was killed)
+    pub footer: Option<Footer>,
+    input: BufReader<File>,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open recording {}", path.display()))?;
+        let mut input = BufReader::new(file);
+
+        let mut magic = [0; 8];
+        input
+            .read_exact(&mut magic)
+            .ok()
+            .filter(|_| &magic == MAGIC)
+            .with_context(|| format!("{} is not a kernel-delay recording", path.display()))?;
+        let version = read_u32(&mut input)?;
+        if version != FORMAT_VERSION {
+            bail!("unsupported recording format version {version}, expected {FORMAT_VERSION}");
+        }
+        let mut header = vec![0; read_u32(&mut input)? as usize];
+        input.read_exact(&mut header)?;
+        let header: Header = serde_json::from_slice(&header).context("invalid recording header")?;
+        if header.event_version != EVENT_VERSION || header.event_size as usize != size_of::<Event>()
+        {
+            bail!(
+                "recording has event layout version {} ({} bytes), this kernel-delay reads version \
+                 {} ({} bytes)",
+                header.event_version,
+                header.event_size,
+                EVENT_VERSION,
+                size_of::<Event>()
+            );
+        }
+
+        Ok(Self {
+            header,
+            footer: None,
+            input,
+        })
+    }
+
+    /// The next event, None at the end of the recording.
+    pub fn next_event(&mut self) -> anyhow::Result<Option<Event>> {
+        let mut tag = [0];
+        if self.input.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        match tag[0] {
+            TAG_EVENT => {
+                // The last event is incomplete when recording was interrupted while writing it
+                let mut data = vec![0; size_of::<Event>()];
+                if self.input.read_exact(&mut data).is_err() {
+                    return Ok(None);
+                }
+                Ok(parse_event(&data))
+            }
+            TAG_FOOTER => {
+                let mut data = vec![0; read_u32(&mut self.input)? as usize];
+                self.input.read_exact(&mut data)?;
+                self.footer =
+                    Some(serde_json::from_slice(&data).context("invalid recording footer")?);
+                Ok(None)
+            }
+            tag => bail!("corrupt recording: unknown frame type {tag}"),
+        }
+    }
+
+    /// Fold all (remaining) events into statistics, like a live run in trace mode does, keeping
+    /// the ones of at least `slow_ns` (when non-zero) as slow events.
+    pub fn read_stats(&mut self, slow_ns: u64) -> anyhow::Result<Stats> {
+        let mut stats = Stats {
+            hist_step_ns: self.header.hist_step_ns,
+            slow_ns,
+            clock_offset_ns: self.header.clock_offset_ns,
+            ..Default::default()
+        };
+        while let Some(event) = self.next_event()? {
+            stats.events_streamed += 1;
+            stats.add_event(&event);
+            stats.add_slow_event(&event, &mut None);
+        }
+        Ok(stats)
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    // Removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("kernel-delay-{}-{name}.kdr", std::process::id());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Raw event as the ring buffer delivers it, only `timestamp`, `tid` and `pid` set
+    fn event_data(timestamp: u64, tid: u32, pid: u32) -> Vec<u8> {
+        let mut data = vec![0; size_of::<Event>()];
+        data[0..8].copy_from_slice(&timestamp.to_ne_bytes());
+        data[8..12].copy_from_slice(&tid.to_ne_bytes());
+        data[12..16].copy_from_slice(&pid.to_ne_bytes());
+        data
+    }
+
+    fn header() -> Header {
+        let targets = Targets {
+            pids: vec![42],
+            ..Default::default()
+        };
+        Header::new("2026-01-01T00:00:00+00:00".to_string(), 10, 1000, targets)
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = TempFile::new("round-trip");
+        let mut recorder = Recorder::create(&file.0, &header()).unwrap();
+        recorder.write_event(&event_data(1, 43, 42)).unwrap();
+        recorder.write_event(&event_data(2, 44, 42)).unwrap();
+        let footer = Footer {
+            stop_time: "2026-01-01T00:00:10+00:00".to_string(),
+            exit_code: Some(3),
+            events_lost: 5,
+        };
+        recorder.finish(&footer).unwrap();
+        assert!(is_recording(&file.0));
+
+        let mut reader = Reader::open(&file.0).unwrap();
+        assert_eq!(reader.header.duration, 10);
+        assert_eq!(reader.header.hist_step_ns, 1000);
+        assert_eq!(reader.header.targets.pids, [42]);
+        let event = reader.next_event().unwrap().unwrap();
+        assert_eq!((event.timestamp, event.tid, event.pid), (1, 43, 42));
+        let event = reader.next_event().unwrap().unwrap();
+        assert_eq!((event.timestamp, event.tid, event.pid), (2, 44, 42));
+        assert!(reader.next_event().unwrap().is_none());
+
+        let footer = reader.footer.unwrap();
+        assert_eq!(footer.exit_code, Some(3));
+        assert_eq!(footer.events_lost, 5);
+    }
+
+    #[test]
+    fn truncated_recording_ends_at_last_complete_event() {
+        let file = TempFile::new("truncated");
+        let mut recorder = Recorder::create(&file.0, &header()).unwrap();
+        recorder.write_event(&event_data(1, 43, 42)).unwrap();
+        recorder.write_event(&event_data(2, 43, 42)).unwrap();
+        // Dropped without a footer, then cut in the middle of the second event
+        drop(recorder);
+        let len = fs::metadata(&file.0).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&file.0)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+
+        let mut reader = Reader::open(&file.0).unwrap();
+        assert_eq!(reader.next_event().unwrap().unwrap().timestamp, 1);
+        assert!(reader.next_event().unwrap().is_none());
+        assert!(reader.footer.is_none());
+    }
+
+    #[test]
+    fn bad_magic_or_version() {
+        let file = TempFile::new("bad-magic");
+        fs::write(&file.0, b"not a recording").unwrap();
+        assert!(!is_recording(&file.0));
+        let error = Reader::open(&file.0).err().unwrap();
+        assert!(error.to_string().ends_with("is not a kernel-delay recording"));
+
+        let file = TempFile::new("bad-version");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&file.0, data).unwrap();
+        assert!(is_recording(&file.0));
+        let error = Reader::open(&file.0).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "unsupported recording format version {}, expected {FORMAT_VERSION}",
+                FORMAT_VERSION + 1
+            )
+        );
+    }
+}
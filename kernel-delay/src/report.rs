@@ -0,0 +1,522 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use kernel_delay_common::hist_slot_range;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errno, get_hardirq_name, get_process_name, get_softirq_name, get_syscall_name, get_thread_name,
+    stats::{ProcessStats, Stat, Stats, ThreadStats},
+};
+
+/// Version of the JSON report layout. Bumped whenever a field is removed, renamed or changes
+/// meaning; adding fields is not considered a breaking change.
+pub const JSON_VERSION: u32 = 1;
+
+/// Machine readable report of one monitoring period (`--format json`), see the README for the
+/// documented layout.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub version: u32,
+    pub start_time: String,
+    pub stop_time: String,
+    pub mode: &'static str,
+    pub targets: Targets,
+    pub events: u64,
+    /// Events sent through the ring buffer (trace mode, slow events)
+    pub events_streamed: u64,
+    /// Events dropped because the ring buffer was full, and their share of all sent events
+    pub events_lost: u64,
+    pub events_lost_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub processes: Vec<ProcessReport>,
+    /// With --slow: every event over the threshold, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub slow_events: Vec<SlowEventReport>,
+    /// With --stacks: the slow events per syscall (or softirq) and stacks
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub slow_stacks: Vec<SlowStackReport>,
+}
+
+/// What was monitored, as given on the command line.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Targets {
+    pub all: bool,
+    pub pids: Vec<u32>,
+    pub pid_tree: bool,
+    pub cgroups: Vec<String>,
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProcessReport {
+    pub pid: u32,
+    pub name: String,
+    pub syscall_ns: u64,
+    pub runq_ns: u64,
+    pub irq_ns: u64,
+    pub threads: Vec<ThreadReport>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThreadReport {
+    pub tid: u32,
+    pub name: String,
+    pub syscalls: Vec<ResourceReport>,
+    /// Total syscall time without poll/ppoll, which is usually idle time
+    pub syscall_total_ns_excluding_poll: u64,
+    pub run: Option<StatReport>,
+    pub ready: Option<StatReport>,
+    pub hardirqs: Vec<ResourceReport>,
+    pub softirqs: Vec<ResourceReport>,
+}
+
+/// One row of the syscall or IRQ tables: a syscall number, IRQ line or softirq vector.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResourceReport {
+    pub name: String,
+    pub number: u32,
+    #[serde(flatten)]
+    pub stat: StatReport,
+    /// Syscalls only: the failed calls per errno, included in `stat`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ErrorReport>,
+}
+
+/// The calls of a syscall that failed with one errno.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub name: String,
+    pub errno: u32,
+    #[serde(flatten)]
+    pub stat: StatReport,
+}
+
+/// A syscall, softirq or run queue wait over the --slow threshold.
+#[derive(Debug, Serialize)]
+pub struct SlowEventReport {
+    /// When it started, RFC 3339 with microseconds
+    pub time: String,
+    pub pid: u32,
+    pub tid: u32,
+    pub thread: String,
+    /// `syscall`, `softirq` or `ready`
+    pub kind: &'static str,
+    /// Syscall or softirq name, empty for `ready`
+    pub name: String,
+    pub latency_ns: u64,
+    /// Syscalls only: the return value, -errno on failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ret: Option<i64>,
+    /// With --stacks: symbolized frames, innermost first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub kernel_stack: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub user_stack: Vec<String>,
+}
+
+/// The slow events of a syscall or softirq that had the same stacks.
+#[derive(Debug, Serialize)]
+pub struct SlowStackReport {
+    pub kind: &'static str,
+    pub name: String,
+    pub count: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub kernel_stack: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub user_stack: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatReport {
+    pub count: u64,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    pub p50_ns: u64,
+    pub p90_ns: u64,
+    pub p99_ns: u64,
+    pub p99_9_ns: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Vec<HistogramSlot>>,
+}
+
+/// Non-empty histogram slot, covering latencies from `low_ns` to `high_ns` inclusive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistogramSlot {
+    pub low_ns: u64,
+    pub high_ns: u64,
+    pub count: u64,
+}
+
+impl StatReport {
+    fn new(stat: &Stat, step_ns: u64, with_histogram: bool) -> Self {
+        let histogram = with_histogram.then(|| {
+            stat.hist
+                .iter()
+                .enumerate()
+                .filter(|&(_, &count)| count > 0)
+                .map(|(slot, &count)| {
+                    let (low_ns, high_ns) = hist_slot_range(slot, step_ns);
+                    HistogramSlot {
+                        low_ns,
+                        high_ns,
+                        count,
+                    }
+                })
+                .collect()
+        });
+        Self {
+            count: stat.count,
+            total_ns: stat.total_ns,
+            min_ns: stat.min_ns,
+            max_ns: stat.max_ns,
+            p50_ns: stat.percentile(50.0, step_ns),
+            p90_ns: stat.percentile(90.0, step_ns),
+            p99_ns: stat.percentile(99.0, step_ns),
+            p99_9_ns: stat.percentile(99.9, step_ns),
+            histogram,
+        }
+    }
+}
+
+/// Reports of the processes `pids`, in that order, with the same rows as the text tables.
+pub fn processes(stats: &Stats, pids: &[u32], with_histogram: bool) -> Vec<ProcessReport> {
+    let interrupt_names = crate::procfs::interrupt_names();
+    pids.iter()
+        .filter_map(|&pid| {
+            let process = stats.processes.get(&pid)?;
+            Some(process_report(
+                stats,
+                pid,
+                process,
+                &interrupt_names,
+                with_histogram,
+            ))
+        })
+        .collect()
+}
+
+fn process_report(
+    stats: &Stats,
+    pid: u32,
+    process: &ProcessStats,
+    interrupt_names: &HashMap<u32, String>,
+    with_histogram: bool,
+) -> ProcessReport {
+    let mut tids: Vec<_> = process.threads.keys().copied().collect();
+    tids.sort();
+    let threads = tids
+        .into_iter()
+        .map(|tid| {
+            thread_report(
+                stats,
+                pid,
+                tid,
+                &process.threads[&tid],
+                interrupt_names,
+                with_histogram,
+            )
+        })
+        .collect();
+
+    ProcessReport {
+        pid,
+        name: get_process_name(pid, process),
+        syscall_ns: process.syscall_ns(),
+        runq_ns: process.runq_ns(),
+        irq_ns: process.irq_ns(),
+        threads,
+    }
+}
+
+fn thread_report(
+    stats: &Stats,
+    pid: u32,
+    tid: u32,
+    thread: &ThreadStats,
+    interrupt_names: &HashMap<u32, String>,
+    with_histogram: bool,
+) -> ThreadReport {
+    let step_ns = stats.hist_step_ns;
+    let stat = |stat: &Stat| StatReport::new(stat, step_ns, with_histogram);
+    // Rows sorted by total time (descending), like the text tables
+    let rows = |stats: &HashMap<u32, Stat>, name: &dyn Fn(u32) -> String| {
+        let mut rows: Vec<_> = stats.iter().collect();
+        rows.sort_by_key(|(_, stat)| std::cmp::Reverse(stat.total_ns));
+        rows.into_iter()
+            .map(|(&number, s)| ResourceReport {
+                name: name(number),
+                number,
+                stat: stat(s),
+                errors: Vec::new(),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut syscalls = rows(&thread.syscalls, &get_syscall_name);
+    for syscall in &mut syscalls {
+        syscall.errors = syscall_errors(thread, syscall.number)
+            .into_iter()
+            .map(|(errno, s)| ErrorReport {
+                name: errno::name(errno),
+                errno,
+                stat: stat(s),
+            })
+            .collect();
+    }
+
+    ThreadReport {
+        tid,
+        name: get_thread_name(&thread.thread_name, tid, pid),
+        syscalls,
+        syscall_total_ns_excluding_poll: thread
+            .syscalls
+            .iter()
+            .filter(|&(&number, _)| !matches!(get_syscall_name(number).as_str(), "poll" | "ppoll"))
+            .map(|(_, stat)| stat.total_ns)
+            .sum(),
+        run: (thread.run.count > 0).then(|| stat(&thread.run)),
+        ready: (thread.ready.count > 0).then(|| stat(&thread.ready)),
+        hardirqs: rows(&thread.hardirqs, &|irq| {
+            get_hardirq_name(irq, &stats.hardirq_names, interrupt_names)
+        }),
+        softirqs: rows(&thread.softirqs, &get_softirq_name),
+    }
+}
+
+/// The slow events of the period, oldest first.
+pub fn slow_events(stats: &Stats) -> Vec<SlowEventReport> {
+    let mut events: Vec<_> = stats.slow_events.iter().collect();
+    events.sort_by_key(|event| event.start_ns);
+    events
+        .into_iter()
+        .map(|event| SlowEventReport {
+            time: chrono::DateTime::from_timestamp_nanos(event.start_ns)
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            pid: event.pid,
+            tid: event.tid,
+            thread: get_thread_name(&event.thread_name, event.tid, event.pid),
+            kind: event.kind,
+            name: match event.kind {
+                "syscall" => get_syscall_name(event.id),
+                "softirq" => get_softirq_name(event.id),
+                _ => String::new(),
+            },
+            latency_ns: event.latency_ns,
+            ret: event.ret,
+            kernel_stack: event.kernel_stack.to_vec(),
+            user_stack: event.user_stack.to_vec(),
+        })
+        .collect()
+}
+
+/// The slow events with stacks grouped by syscall (or softirq) and stacks, by total latency
+/// (descending).
+pub fn slow_stacks(stats: &Stats) -> Vec<SlowStackReport> {
+    let mut groups: HashMap<_, SlowStackReport> = HashMap::new();
+    for event in &stats.slow_events {
+        if event.kernel_stack.is_empty() && event.user_stack.is_empty() {
+            continue;
+        }
+        let key = (
+            event.kind,
+            event.id,
+            event.kernel_stack.clone(),
+            event.user_stack.clone(),
+        );
+        let group = groups.entry(key).or_insert_with(|| SlowStackReport {
+            kind: event.kind,
+            name: match event.kind {
+                "syscall" => get_syscall_name(event.id),
+                _ => get_softirq_name(event.id),
+            },
+            count: 0,
+            total_ns: 0,
+            max_ns: 0,
+            kernel_stack: event.kernel_stack.to_vec(),
+            user_stack: event.user_stack.to_vec(),
+        });
+        group.count += 1;
+        group.total_ns += event.latency_ns;
+        group.max_ns = group.max_ns.max(event.latency_ns);
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    groups.sort_by(|a, b| {
+        b.total_ns
+            .cmp(&a.total_ns)
+            .then_with(|| (a.kind, &a.name).cmp(&(b.kind, &b.name)))
+    });
+    groups
+}
+
+/// Errors of syscall `number` as (errno, statistics), by total time (descending).
+pub fn syscall_errors(thread: &ThreadStats, number: u32) -> Vec<(u32, &Stat)> {
+    let mut errors: Vec<_> = thread
+        .syscall_errors
+        .iter()
+        .filter(|&(&(n, _), _)| n == number)
+        .map(|(&(_, errno), stat)| (errno, stat))
+        .collect();
+    errors.sort_by_key(|&(errno, stat)| (std::cmp::Reverse(stat.total_ns), errno));
+    errors
+}
+
+const DELIMITED_HEADER: [&str; 16] = [
+    "time", "pid", "process", "tid", "thread", "kind", "name", "number", "count", "total_ns",
+    "min_ns", "max_ns", "p50_ns", "p90_ns", "p99_ns", "p99_9_ns",
+];
+
+/// Write the reports as CSV (`separator` ',') or TSV ('\t'): optionally a header line, then one
+/// line per thread and resource (syscall, syscall error, run, ready, hard IRQ or soft IRQ) with
+/// raw numbers. `time` is the end of the period the reports cover.
+pub fn write_delimited(
+    out: &mut impl Write,
+    processes: &[ProcessReport],
+    separator: char,
+    time: &str,
+    header: bool,
+) -> io::Result<()> {
+    if header {
+        write_record(
+            out,
+            separator,
+            DELIMITED_HEADER.iter().map(|&h| h.to_string()),
+        )?;
+    }
+    for process in processes {
+        for thread in &process.threads {
+            // Same order as the text report
+            let rows = [
+                resource_rows("syscall", &thread.syscalls),
+                error_rows(&thread.syscalls),
+                scheduler_rows("run", &thread.run),
+                scheduler_rows("ready", &thread.ready),
+                resource_rows("hardirq", &thread.hardirqs),
+                resource_rows("softirq", &thread.softirqs),
+            ];
+
+            for (kind, name, number, stat) in rows.into_iter().flatten() {
+                let record = [
+                    time.to_string(),
+                    process.pid.to_string(),
+                    process.name.clone(),
+                    thread.tid.to_string(),
+                    thread.name.clone(),
+                    kind.to_string(),
+                    name,
+                    number,
+                    stat.count.to_string(),
+                    stat.total_ns.to_string(),
+                    stat.min_ns.to_string(),
+                    stat.max_ns.to_string(),
+                    stat.p50_ns.to_string(),
+                    stat.p90_ns.to_string(),
+                    stat.p99_ns.to_string(),
+                    stat.p99_9_ns.to_string(),
+                ];
+                write_record(out, separator, record.into_iter())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// kind, name, number and statistics of one CSV/TSV line
+type Row<'a> = (&'static str, String, String, &'a StatReport);
+
+fn resource_rows<'a>(kind: &'static str, resources: &'a [ResourceReport]) -> Vec<Row<'a>> {
+    resources
+        .iter()
+        .map(|r| (kind, r.name.clone(), r.number.to_string(), &r.stat))
+        .collect()
+}
+
+// `name` is the syscall and the errno, e.g. `read:EAGAIN`
+fn error_rows(syscalls: &[ResourceReport]) -> Vec<Row<'_>> {
+    syscalls
+        .iter()
+        .flat_map(|syscall| {
+            syscall.errors.iter().map(|error| {
+                (
+                    "syscall_error",
+                    format!("{}:{}", syscall.name, error.name),
+                    syscall.number.to_string(),
+                    &error.stat,
+                )
+            })
+        })
+        .collect()
+}
+
+fn scheduler_rows<'a>(kind: &'static str, stat: &'a Option<StatReport>) -> Vec<Row<'a>> {
+    stat.iter()
+        .map(|stat| (kind, String::new(), String::new(), stat))
+        .collect()
+}
+
+// CSV fields are quoted when needed (RFC 4180). TSV has no quoting, so tabs and line breaks in
+// names are replaced by spaces.
+fn write_record(
+    out: &mut impl Write,
+    separator: char,
+    fields: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let fields: Vec<String> = fields
+        .map(|field| {
+            if separator == '\t' {
+                field.replace(['\t', '\n', '\r'], " ")
+            } else if field.contains([separator, '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect();
+    writeln!(out, "{}", fields.join(&separator.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(separator: char, fields: &[&str]) -> String {
+        let mut out = Vec::new();
+        write_record(&mut out, separator, fields.iter().map(|f| f.to_string())).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_quotes_only_when_needed() {
+        assert_eq!(record(',', &["1", "nginx", ""]), "1,nginx,\n");
+        assert_eq!(record(',', &["a,b", "tab\there"]), "\"a,b\",tab\there\n");
+    }
+
+    #[test]
+    fn csv_doubles_quotes_and_keeps_line_breaks_quoted() {
+        assert_eq!(record(',', &["say \"hi\""]), "\"say \"\"hi\"\"\"\n");
+        assert_eq!(record(',', &["two\nlines", "cr\r"]), "\"two\nlines\",\"cr\r\"\n");
+    }
+
+    #[test]
+    fn tsv_replaces_tabs_and_line_breaks() {
+        assert_eq!(record('\t', &["1", "a,b", "\"q\""]), "1\ta,b\t\"q\"\n");
+        assert_eq!(record('\t', &["tab\there", "two\nlines\r"]), "tab here\ttwo lines \n");
+    }
+
+    #[test]
+    fn header_only_when_asked() {
+        let mut out = Vec::new();
+        write_delimited(&mut out, &[], '\t', "2026-01-01T00:00:00+00:00", true).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), DELIMITED_HEADER.join("\t") + "\n");
+
+        let mut out = Vec::new();
+        write_delimited(&mut out, &[], ',', "2026-01-01T00:00:00+00:00", false).unwrap();
+        assert!(out.is_empty());
+    }
+}
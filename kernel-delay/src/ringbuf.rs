@@ -0,0 +1,76 @@
+use std::mem::size_of;
+
+use aya::maps::{MapData, RingBuf};
+use kernel_delay_common::Event;
+use log::warn;
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{mpsc, oneshot},
+};
+
+/// Size of an event in the ring buffer and in the batches.
+pub const EVENT_SIZE: usize = size_of::<Event>();
+
+/// Reads the ring buffer in a task of its own as soon as the kernel signals data, so that bursts
+/// don't wait for the collection loop, and hands the events on in batches: the raw events read
+/// on one wakeup, back to back.
+pub struct EventReader {
+    pub batches: mpsc::UnboundedReceiver<Vec<u8>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl EventReader {
+    pub fn spawn(ring_buf: RingBuf<MapData>) -> anyhow::Result<Self> {
+        let mut ring_buf = AsyncFd::with_interest(ring_buf, tokio::io::Interest::READABLE)?;
+        let (sender, batches) = mpsc::unbounded_channel();
+        let (stop, mut stopped) = oneshot::channel();
+
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    guard = ring_buf.readable_mut() => {
+                        let mut guard = match guard {
+                            Ok(guard) => guard,
+                            Err(e) => {
+                                warn!("Failed to wait for the ring buffer: {}", e);
+                                return;
+                            }
+                        };
+                        let batch = drain(guard.get_inner_mut());
+                        guard.clear_ready();
+                        if !batch.is_empty() && sender.send(batch).is_err() {
+                            return;
+                        }
+                    }
+                    _ = &mut stopped => {
+                        // What the kernel submitted until now, then close the channel
+                        let _ = sender.send(drain(ring_buf.get_mut()));
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            batches,
+            stop: Some(stop),
+        })
+    }
+
+    /// Read what is left in the ring buffer and stop, `batches` ends after those events.
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+fn drain(ring_buf: &mut RingBuf<MapData>) -> Vec<u8> {
+    let mut batch = Vec::new();
+    while let Some(item) = ring_buf.next() {
+        if item.len() >= EVENT_SIZE {
+            batch.extend_from_slice(&item[..EVENT_SIZE]);
+        }
+    }
+    batch
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use anyhow::Context as _;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    signal::unix::{SignalKind, signal},
+};
+
+use crate::{Mode, ServeArgs, metrics, stats::Stats, tracer::Tracer};
+
+// A scrape request is a few hundred bytes, anything bigger is not a scraper
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keep the eBPF programs attached, aggregating in the kernel, and answer `GET /metrics` with
+/// everything measured since startup until interrupted (Ctrl-C or SIGTERM).
+///
+/// Requests are handled one after the other: reading the maps needs the `Ebpf` and a scrape
+/// every few seconds doesn't need more.
+pub async fn run(args: &ServeArgs) -> anyhow::Result<()> {
+    let mut tracer = Tracer::start(&args.trace, None, Mode::Aggregate, None, 0, false)?;
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", args.listen))?;
+    println!(
+        "# Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut rescan = tokio::time::interval(Duration::from_secs(1));
+    // Measurements of the exited threads whose entries were removed from the maps
+    let mut events_removed = 0;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let scrape = || scrape(&mut tracer, args, &mut events_removed);
+                let handled = tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, scrape)).await;
+                match handled {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("Request from {} failed: {:#}", peer, e),
+                    Err(_) => debug!("Request from {} timed out", peer),
+                }
+            }
+            _ = rescan.tick() => {
+                tracer.rescan_cgroups();
+                tracer.forget_exited_pids();
+            }
+            _ = sigint.recv() => break,
+            _ = sigterm.recv() => break,
+        }
+    }
+
+    info!("Exiting...");
+    Ok(())
+}
+
+// Everything measured since startup, rendered for a scrape
+fn scrape(
+    tracer: &mut Tracer,
+    args: &ServeArgs,
+    events_removed: &mut u64,
+) -> anyhow::Result<String> {
+    let mut stats = Stats {
+        hist_step_ns: args.trace.hist_linear.unwrap_or(0),
+        ..Default::default()
+    };
+    stats.read_maps(&mut tracer.ebpf, false)?;
+    // Counters never go down: the measurements of the threads removed so far still count
+    stats.events += *events_removed;
+    let body = metrics::render(&stats);
+    // Exited threads were just exported one last time
+    *events_removed += Stats::remove_exited(&mut tracer.ebpf)?;
+    Ok(body)
+}
+
+// Answer one request, `scrape` renders the metrics
+async fn handle(
+    mut stream: TcpStream,
+    scrape: impl FnOnce() -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    // Only the request line matters, the headers are read and ignored
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_LEN {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+
+    // The query string is ignored, like in most exporters
+    match (
+        method,
+        path.map(|path| path.split('?').next().unwrap_or_default()),
+    ) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = scrape()?;
+            respond(&mut stream, "200 OK", &body).await
+        }
+        (Some("GET"), Some("/")) => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "kernel-delay: metrics are at /metrics\n",
+            )
+            .await
+        }
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> anyhow::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send one request to `handle`, rendering "metrics\n" on scrape, and return the response
+    async fn request(request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(stream, || Ok("metrics\n".to_string()))
+                .await
+                .unwrap();
+        };
+        tokio::join!(client, server).0
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.contains("\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("\r\n\r\nmetrics\n"), "{response}");
+
+        let response = request("GET /metrics?name[]=x HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_are_not_found() {
+        let response = request("GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+        let response = request("POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{response}"
+        );
+    }
+}
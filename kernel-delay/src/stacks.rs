@@ -0,0 +1,301 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use aya::maps::{MapData, StackTraceMap};
+use log::debug;
+use object::{Object as _, ObjectSegment as _, ObjectSymbol as _, SymbolKind};
+
+/// Symbolized frames of a stack, innermost first. Empty when it was not captured.
+pub type Stack = Arc<[String]>;
+
+/// Reads the stacks the eBPF programs captured into the STACKS map (`--stacks`) and names their
+/// frames: kernel addresses from /proc/kallsyms, user addresses from the mappings of the process
+/// and the symbol tables of the mapped ELF files. Done as the events arrive, while the processes
+/// are still around.
+pub struct Stacks {
+    map: StackTraceMap<MapData>,
+    kernel_symbols: Option<KernelSymbols>,
+    mappings: HashMap<u32, Vec<Mapping>>,
+    // By device and inode, shared by every process mapping the same file
+    files: HashMap<(String, u64), Option<Arc<ElfSymbols>>>,
+    // Without BPF_F_REUSE_STACKID an id keeps its stack, so it is only symbolized once
+    kernel_stacks: HashMap<i32, Stack>,
+    user_stacks: HashMap<(u32, i32), Stack>,
+}
+
+impl Stacks {
+    pub fn new(map: StackTraceMap<MapData>) -> Self {
+        Self {
+            map,
+            kernel_symbols: None,
+            mappings: HashMap::new(),
+            files: HashMap::new(),
+            kernel_stacks: HashMap::new(),
+            user_stacks: HashMap::new(),
+        }
+    }
+
+    /// Kernel stack `id` (`Event::kernel_stack_id`), symbolized.
+    pub fn kernel(&mut self, id: i32) -> Stack {
+        if id < 0 {
+            return Stack::default();
+        }
+        if let Some(stack) = self.kernel_stacks.get(&id) {
+            return stack.clone();
+        }
+        let ips = self.read(id);
+        let symbols = self.kernel_symbols.get_or_insert_with(KernelSymbols::load);
+        let stack: Stack = ips.iter().map(|&ip| symbols.symbolize(ip)).collect();
+        self.kernel_stacks.insert(id, stack.clone());
+        stack
+    }
+
+    /// User stack `id` (`Event::user_stack_id`) of process `pid`, symbolized.
+    pub fn user(&mut self, pid: u32, id: i32) -> Stack {
+        if id < 0 {
+            return Stack::default();
+        }
+        if let Some(stack) = self.user_stacks.get(&(pid, id)) {
+            return stack.clone();
+        }
+        let ips = self.read(id);
+        let stack: Stack = ips.iter().map(|&ip| self.symbolize_user(pid, ip)).collect();
+        self.user_stacks.insert((pid, id), stack.clone());
+        stack
+    }
+
+    fn read(&self, id: i32) -> Vec<u64> {
+        match self.map.get(&(id as u32), 0) {
+            Ok(trace) => trace.frames().iter().map(|frame| frame.ip).collect(),
+            Err(e) => {
+                debug!("Failed to read stack {}: {}", id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    // `symbol+0x1f (libc.so.6)`, or the address when it can't be named
+    fn symbolize_user(&mut self, pid: u32, ip: u64) -> String {
+        // Libraries come and go (dlopen), read the mappings again for an unknown address
+        let known = self
+            .mappings
+            .get(&pid)
+            .is_some_and(|mappings| find_mapping(mappings, ip).is_some());
+        if !known {
+            self.mappings.insert(pid, read_mappings(pid));
+        }
+        let Some(mapping) = self
+            .mappings
+            .get(&pid)
+            .and_then(|mappings| find_mapping(mappings, ip))
+            .cloned()
+        else {
+            return format!("0x{ip:x}");
+        };
+        // Anonymous, e.g. JIT compiled code
+        let Some(path) = &mapping.path else {
+            return format!("0x{ip:x}");
+        };
+
+        let file_offset = ip - mapping.start + mapping.offset;
+        let module = path.rsplit('/').next().unwrap_or(path);
+        let elf = self
+            .files
+            .entry((mapping.dev.clone(), mapping.inode))
+            .or_insert_with(|| ElfSymbols::load(pid, path).map(Arc::new));
+        match elf.as_ref().and_then(|elf| elf.symbolize(file_offset)) {
+            Some(symbol) => format!("{symbol} ({module})"),
+            None => format!("0x{file_offset:x} ({module})"),
+        }
+    }
+}
+
+// Code symbols of the running kernel and its modules
+struct KernelSymbols {
+    // (address, name, module), sorted by address
+    symbols: Vec<(u64, String, Option<String>)>,
+}
+
+impl KernelSymbols {
+    // `/proc/kallsyms` lines are `address type name [module]`, the addresses are all 0 without
+    // CAP_SYSLOG
+    fn load() -> Self {
+        let text = fs::read_to_string("/proc/kallsyms").unwrap_or_default();
+        let mut symbols: Vec<_> = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                if address == 0 || !matches!(kind, "t" | "T" | "w" | "W") {
+                    return None;
+                }
+                Some((address, name.to_string(), fields.next().map(str::to_string)))
+            })
+            .collect();
+        symbols.sort_unstable_by_key(|(address, _, _)| *address);
+        if symbols.is_empty() {
+            debug!("No kernel symbols in /proc/kallsyms, kernel frames are left as addresses");
+        }
+        Self { symbols }
+    }
+
+    // `symbol+0x1f`, followed by the module when not built in
+    fn symbolize(&self, ip: u64) -> String {
+        let index = self
+            .symbols
+            .partition_point(|(address, _, _)| *address <= ip);
+        match index.checked_sub(1).map(|index| &self.symbols[index]) {
+            Some((address, name, Some(module))) => format!("{name}+0x{:x} {module}", ip - address),
+            Some((address, name, None)) => format!("{name}+0x{:x}", ip - address),
+            None => format!("0x{ip:x}"),
+        }
+    }
+}
+
+// An executable mapping of `/proc/<pid>/maps`
+#[derive(Clone)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    dev: String,
+    inode: u64,
+    // None when anonymous or special (e.g. [vdso]), without a file to read symbols from
+    path: Option<String>,
+}
+
+// Lines are `start-end perms offset dev inode`, then the path after some padding
+fn read_mappings(pid: u32) -> Vec<Mapping> {
+    let Ok(text) = fs::read_to_string(format!("/proc/{pid}/maps")) else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, ' ');
+            let (start, end) = fields.next()?.split_once('-')?;
+            if !fields.next()?.contains('x') {
+                return None;
+            }
+            Some(Mapping {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                offset: u64::from_str_radix(fields.next()?, 16).ok()?,
+                dev: fields.next()?.to_string(),
+                inode: fields.next()?.parse().ok()?,
+                path: fields
+                    .next()
+                    .map(str::trim_start)
+                    .filter(|path| path.starts_with('/'))
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+fn find_mapping(mappings: &[Mapping], ip: u64) -> Option<&Mapping> {
+    mappings
+        .iter()
+        .find(|mapping| (mapping.start..mapping.end).contains(&ip))
+}
+
+// Function symbols of an ELF file, and how its file offsets map to the addresses they use
+struct ElfSymbols {
+    // Loadable segments as (file offset, size in the file, virtual address)
+    segments: Vec<(u64, u64, u64)>,
+    // (address, size, name), sorted by address
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl ElfSymbols {
+    // Read through the root of the process, which may be in another mount namespace (containers)
+    fn load(pid: u32, path: &str) -> Option<Self> {
+        let data = fs::read(format!("/proc/{pid}/root{path}"))
+            .map_err(|e| debug!("Failed to read {}: {}", path, e))
+            .ok()?;
+        let file = object::File::parse(&*data)
+            .map_err(|e| debug!("Failed to parse {}: {}", path, e))
+            .ok()?;
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        // Stripped binaries only have the dynamic symbols
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?.to_string();
+                Some((symbol.address(), symbol.size(), name))
+            })
+            .collect();
+        symbols.sort_unstable_by_key(|(address, _, _)| *address);
+        symbols.dedup_by_key(|(address, _, _)| *address);
+        Some(Self { segments, symbols })
+    }
+
+    // `symbol+0x1f` of the code at `file_offset`
+    fn symbolize(&self, file_offset: u64) -> Option<String> {
+        let &(offset, _, address) = self
+            .segments
+            .iter()
+            .find(|&&(offset, size, _)| (offset..offset + size).contains(&file_offset))?;
+        let address = file_offset - offset + address;
+        let index = self
+            .symbols
+            .partition_point(|(start, _, _)| *start <= address)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.symbols[index];
+        (*size == 0 || address < start + size)
+            .then(|| format!("{}+0x{:x}", demangle(name), address - start))
+    }
+}
+
+// Readable name of a Rust (without the hash) or C++ symbol, others are left as they are. Rust's
+// legacy mangling is valid C++ mangling as well, so it is tried first.
+fn demangle(name: &str) -> String {
+    if let Ok(symbol) = rustc_demangle::try_demangle(name) {
+        return format!("{symbol:#}");
+    }
+    if name.starts_with("_Z") {
+        let demangled = cpp_demangle::Symbol::new(name)
+            .ok()
+            .and_then(|symbol| symbol.demangle(&Default::default()).ok());
+        if let Some(demangled) = demangled {
+            return demangled;
+        }
+    }
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangles_rust_and_cpp() {
+        assert_eq!(
+            demangle("_ZN12kernel_delay6stacks6Stacks4user17h0123456789abcdefE"),
+            "kernel_delay::stacks::Stacks::user"
+        );
+        assert_eq!(
+            demangle("_RNvMNtCs1234_12kernel_delay6stacksNtB2_6Stacks4user"),
+            "<kernel_delay::stacks::Stacks>::user"
+        );
+        assert_eq!(demangle("_ZN3foo3barEv"), "foo::bar()");
+        assert_eq!(
+            demangle("_ZNSt6vectorIiSaIiEE9push_backERKi"),
+            "std::vector<int, std::allocator<int> >::push_back(int const&)"
+        );
+    }
+
+    #[test]
+    fn leaves_c_symbols() {
+        assert_eq!(demangle("pthread_cond_wait"), "pthread_cond_wait");
+        assert_eq!(demangle("_Znot_mangled"), "_Znot_mangled");
+    }
+}
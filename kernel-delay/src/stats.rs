@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+
+use aya::{
+    Ebpf,
+    maps::{HashMap as AyaHashMap, PerCpuArray, PerCpuHashMap},
+};
+use kernel_delay_common::{
+    Event, EventType, HIST_SLOTS, HistKey, MAX_ERRNO, RINGBUF_DROPPED, RINGBUF_RESERVED, StatKey,
+    StatValue, hist_slot, hist_slot_range,
+};
+
+use crate::{
+    procfs,
+    stacks::{Stack, Stacks},
+};
+
+/// Count and latency summary of one resource (a syscall, an IRQ, thread run/ready time, ...).
+#[derive(Clone, Debug, Default)]
+pub struct Stat {
+    pub count: u64,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    /// Latency distribution, indexed by `hist_slot()`. Empty until the first sample.
+    pub hist: Vec<u64>,
+}
+
+impl Stat {
+    pub fn add(&mut self, count: u64, total_ns: u64, min_ns: u64, max_ns: u64) {
+        if count == 0 {
+            return;
+        }
+        if self.count == 0 || min_ns < self.min_ns {
+            self.min_ns = min_ns;
+        }
+        if max_ns > self.max_ns {
+            self.max_ns = max_ns;
+        }
+        self.count += count;
+        self.total_ns += total_ns;
+    }
+
+    pub fn add_hist(&mut self, slot: usize, count: u64) {
+        if self.hist.is_empty() {
+            self.hist = vec![0; HIST_SLOTS];
+        }
+        self.hist[slot.min(HIST_SLOTS - 1)] += count;
+    }
+
+    /// Estimate the `p`th percentile (0-100) from the histogram, interpolating linearly inside
+    /// the slot it falls into. The result is kept within the exact min/max.
+    pub fn percentile(&self, p: f64, step_ns: u64) -> u64 {
+        let samples: u64 = self.hist.iter().sum();
+        if samples == 0 {
+            return 0;
+        }
+
+        // The product lands a hair above a whole rank more often than not (99.9% of 1000 samples
+        // is 999.0000000000001), which would move the estimate up by one sample
+        let rank = (p / 100.0) * samples as f64;
+        let rank = (rank - rank * 1e-12).ceil().max(1.0);
+        let mut seen = 0u64;
+        for (slot, &count) in self.hist.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            if (seen + count) as f64 >= rank {
+                let (low, high) = hist_slot_range(slot, step_ns);
+                let fraction = (rank - seen as f64) / count as f64;
+                let value = low as f64 + (high - low) as f64 * fraction;
+                return (value as u64).clamp(self.min_ns, self.max_ns);
+            }
+            seen += count;
+        }
+        self.max_ns
+    }
+}
+
+/// Everything measured for one thread.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadStats {
+    pub thread_name: [u8; 16],
+    pub syscalls: HashMap<u32, Stat>,
+    /// The failed syscalls of `syscalls`, by syscall number and errno.
+    pub syscall_errors: HashMap<(u32, u32), Stat>,
+    pub run: Stat,
+    pub ready: Stat,
+    pub hardirqs: HashMap<u32, Stat>,
+    pub softirqs: HashMap<u32, Stat>,
+}
+
+/// Everything measured for one process, per thread.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessStats {
+    pub threads: HashMap<u32, ThreadStats>,
+}
+
+impl ProcessStats {
+    /// Time spent in syscalls by all threads.
+    pub fn syscall_ns(&self) -> u64 {
+        self.threads
+            .values()
+            .flat_map(|thread| thread.syscalls.values())
+            .map(|stat| stat.total_ns)
+            .sum()
+    }
+
+    /// Time runnable threads waited for a CPU.
+    pub fn runq_ns(&self) -> u64 {
+        self.threads
+            .values()
+            .map(|thread| thread.ready.total_ns)
+            .sum()
+    }
+
+    /// Time threads were interrupted by hard and soft IRQs.
+    pub fn irq_ns(&self) -> u64 {
+        self.threads
+            .values()
+            .flat_map(|thread| thread.hardirqs.values().chain(thread.softirqs.values()))
+            .map(|stat| stat.total_ns)
+            .sum()
+    }
+}
+
+/// A syscall, softirq or run queue wait that took at least `Stats::slow_ns`, as streamed.
+#[derive(Clone, Debug)]
+pub struct SlowEvent {
+    /// Wall-clock time it started at, in nanoseconds since the epoch
+    pub start_ns: i64,
+    pub pid: u32,
+    pub tid: u32,
+    pub thread_name: [u8; 16],
+    /// `syscall`, `softirq` or `ready`, like in the CSV output
+    pub kind: &'static str,
+    /// Syscall number or softirq vector
+    pub id: u32,
+    pub latency_ns: u64,
+    /// Return value of a syscall, -errno on failure
+    pub ret: Option<i64>,
+    /// With --stacks, syscalls and softirqs only
+    pub kernel_stack: Stack,
+    pub user_stack: Stack,
+}
+
+/// Per-process and per-thread statistics of a monitoring period, fed either from the raw event
+/// stream or from the in-kernel aggregation maps.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub processes: HashMap<u32, ProcessStats>,
+    /// Handler names of the hard IRQ lines seen, as reported by the tracepoint.
+    pub hardirq_names: HashMap<u32, String>,
+    /// Number of individual measurements folded in.
+    pub events: u64,
+    /// Width of the linear histogram slots, 0 for log2 slots. Must match `Config::hist_step_ns`.
+    pub hist_step_ns: u64,
+    /// Events sent through the ring buffer (trace mode, slow events).
+    pub events_streamed: u64,
+    /// Events sent in the periods before this one, like `events_lost_before`.
+    pub events_streamed_before: u64,
+    /// Events dropped in the kernel because the ring buffer was full.
+    pub events_lost: u64,
+    /// Drops counted in the periods before this one, the kernel counter is never cleared.
+    pub events_lost_before: u64,
+    /// Children of target processes not followed because TARGET_PIDS was full, since
+    /// monitoring started.
+    pub forks_missed: u64,
+    /// Latency from which events are kept in `slow_events`, 0 for none (`--slow`).
+    pub slow_ns: u64,
+    /// CLOCK_REALTIME minus CLOCK_MONOTONIC, which the event timestamps are taken from.
+    pub clock_offset_ns: i64,
+    pub slow_events: Vec<SlowEvent>,
+}
+
+impl Stats {
+    pub fn add_event(&mut self, event: &Event) {
+        let thread = self
+            .processes
+            .entry(event.pid)
+            .or_default()
+            .threads
+            .entry(event.tid)
+            .or_default();
+        thread.thread_name = event.thread_name;
+
+        // Every streamed event carries a single measurement, so its max is also its min
+        let (stat, count, total_ns, latency) = match event.event_type {
+            x if x == EventType::SyscallStats as u32 => {
+                let stat = &event.syscall_stat;
+                if (-MAX_ERRNO..0).contains(&stat.ret) {
+                    let errno = (-stat.ret) as u32;
+                    let error = thread
+                        .syscall_errors
+                        .entry((stat.number, errno))
+                        .or_default();
+                    error.add(1, stat.total_ns, stat.max_ns, stat.max_ns);
+                    error.add_hist(hist_slot(stat.max_ns, self.hist_step_ns), 1);
+                }
+                (
+                    thread.syscalls.entry(stat.number).or_default(),
+                    stat.count as u64,
+                    stat.total_ns,
+                    stat.max_ns,
+                )
+            }
+            x if x == EventType::ThreadRunStats as u32 => {
+                let stat = &event.thread_run_stat;
+                (
+                    &mut thread.run,
+                    stat.sched_cnt as u64,
+                    stat.total_ns,
+                    stat.max_ns,
+                )
+            }
+            x if x == EventType::ThreadReadyStats as u32 => {
+                let stat = &event.thread_ready_stat;
+                (
+                    &mut thread.ready,
+                    stat.sched_cnt as u64,
+                    stat.total_ns,
+                    stat.max_ns,
+                )
+            }
+            x if x == EventType::HardIrqStats as u32 => {
+                let stat = &event.irq_stat;
+                let name = String::from_utf8_lossy(&stat.name);
+                let name = name.trim_end_matches('\0');
+                if !name.is_empty() {
+                    self.hardirq_names.insert(stat.vector, name.to_string());
+                }
+                (
+                    thread.hardirqs.entry(stat.vector).or_default(),
+                    stat.count as u64,
+                    stat.total_ns,
+                    stat.max_ns,
+                )
+            }
+            x if x == EventType::SoftIrqStats as u32 => {
+                let stat = &event.irq_stat;
+                (
+                    thread.softirqs.entry(stat.vector).or_default(),
+                    stat.count as u64,
+                    stat.total_ns,
+                    stat.max_ns,
+                )
+            }
+            _ => return,
+        };
+        stat.add(count, total_ns, latency, latency);
+        stat.add_hist(hist_slot(latency, self.hist_step_ns), count);
+        self.events += 1;
+    }
+
+    /// Keep `event` in `slow_events` if it is a syscall, softirq or run queue wait of at least
+    /// `slow_ns`. Streamed in trace mode, or just those in aggregate mode. Its stacks are read
+    /// from `stacks` when they were captured.
+    pub fn add_slow_event(&mut self, event: &Event, stacks: &mut Option<Stacks>) {
+        if self.slow_ns == 0 {
+            return;
+        }
+        let (kind, id, latency_ns, ret) = match event.event_type {
+            x if x == EventType::SyscallStats as u32 => {
+                let stat = &event.syscall_stat;
+                ("syscall", stat.number, stat.max_ns, Some(stat.ret))
+            }
+            x if x == EventType::SoftIrqStats as u32 => (
+                "softirq",
+                event.irq_stat.vector,
+                event.irq_stat.max_ns,
+                None,
+            ),
+            x if x == EventType::ThreadReadyStats as u32 => {
+                ("ready", 0, event.thread_ready_stat.max_ns, None)
+            }
+            _ => return,
+        };
+        if latency_ns < self.slow_ns {
+            return;
+        }
+        let (kernel_stack, user_stack) = match stacks {
+            Some(stacks) => (
+                stacks.kernel(event.kernel_stack_id),
+                stacks.user(event.pid, event.user_stack_id),
+            ),
+            None => Default::default(),
+        };
+        // Events are timestamped when they end
+        let start = event.timestamp.saturating_sub(latency_ns);
+        self.slow_events.push(SlowEvent {
+            start_ns: start as i64 + self.clock_offset_ns,
+            pid: event.pid,
+            tid: event.tid,
+            thread_name: event.thread_name,
+            kind,
+            id,
+            latency_ns,
+            ret,
+            kernel_stack,
+            user_stack,
+        });
+    }
+
+    fn stat_mut(&mut self, key: &StatKey) -> Option<&mut Stat> {
+        let thread = self
+            .processes
+            .entry(key.pid)
+            .or_default()
+            .threads
+            .entry(key.tid)
+            .or_default();
+        match key.kind {
+            x if x == EventType::SyscallStats as u32 => {
+                Some(thread.syscalls.entry(key.id).or_default())
+            }
+            x if x == EventType::ThreadRunStats as u32 => Some(&mut thread.run),
+            x if x == EventType::ThreadReadyStats as u32 => Some(&mut thread.ready),
+            x if x == EventType::HardIrqStats as u32 => {
+                Some(thread.hardirqs.entry(key.id).or_default())
+            }
+            x if x == EventType::SoftIrqStats as u32 => {
+                Some(thread.softirqs.entry(key.id).or_default())
+            }
+            x if x == EventType::SyscallErrorStats as u32 => {
+                let (number, errno) = (key.id & 0xFFFF, key.id >> 16);
+                Some(thread.syscall_errors.entry((number, errno)).or_default())
+            }
+            _ => None,
+        }
+    }
+
+    /// Fold in one per-CPU value of the in-kernel STATS map.
+    pub fn add_stat(&mut self, key: &StatKey, value: &StatValue) {
+        let Some(stat) = self.stat_mut(key) else {
+            return;
+        };
+        stat.add(value.count, value.total_ns, value.min_ns, value.max_ns);
+        // Failed syscalls are counted in their SyscallStats entry already
+        if key.kind != EventType::SyscallErrorStats as u32 {
+            self.events += value.count;
+        }
+    }
+
+    /// Fold in one per-CPU counter of the in-kernel HIST map.
+    pub fn add_hist(&mut self, key: &HistKey, count: u64) {
+        if let Some(stat) = self.stat_mut(&key.key) {
+            stat.add_hist(key.slot as usize, count);
+        }
+    }
+
+    /// Fold in everything the eBPF programs accumulated in their maps. With `clear`, entries are
+    /// removed as they are read, so that the next call only sees what happened in between
+    /// (measurements landing between reading and removing an entry are lost).
+    pub fn read_maps(&mut self, ebpf: &mut Ebpf, clear: bool) -> anyhow::Result<()> {
+        let mut stats_map: PerCpuHashMap<_, StatKey, StatValue> =
+            PerCpuHashMap::try_from(ebpf.map_mut("STATS").unwrap())?;
+        let keys = stats_map.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in keys {
+            let Ok(values) = stats_map.get(&key, 0) else {
+                continue;
+            };
+            if clear {
+                stats_map.remove(&key)?;
+            }
+            for value in values.iter() {
+                self.add_stat(&key, value);
+            }
+        }
+
+        let mut hist_map: PerCpuHashMap<_, HistKey, u64> =
+            PerCpuHashMap::try_from(ebpf.map_mut("HIST").unwrap())?;
+        let keys = hist_map.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in keys {
+            let Ok(values) = hist_map.get(&key, 0) else {
+                continue;
+            };
+            if clear {
+                hist_map.remove(&key)?;
+            }
+            self.add_hist(&key, values.iter().sum());
+        }
+
+        let names_map: AyaHashMap<_, u32, [u8; 16]> =
+            AyaHashMap::try_from(ebpf.map("HARDIRQ_NAMES").unwrap())?;
+        for item in names_map.iter() {
+            let (irq, name) = item?;
+            let name = String::from_utf8_lossy(&name);
+            let name = name.trim_end_matches('\0');
+            if !name.is_empty() {
+                self.hardirq_names.insert(irq, name.to_string());
+            }
+        }
+
+        // Names of the threads measured, including the ones that exited
+        let thread_names: AyaHashMap<_, u32, [u8; 16]> =
+            AyaHashMap::try_from(ebpf.map("THREAD_NAMES").unwrap())?;
+        for process in self.processes.values_mut() {
+            for (tid, thread) in process.threads.iter_mut() {
+                if let Ok(name) = thread_names.get(tid, 0) {
+                    thread.thread_name = name;
+                }
+            }
+        }
+
+        let counters: PerCpuArray<_, u64> =
+            PerCpuArray::try_from(ebpf.map("RINGBUF_COUNTERS").unwrap())?;
+        let reserved: u64 = counters.get(&RINGBUF_RESERVED, 0)?.iter().sum();
+        self.events_streamed = reserved.saturating_sub(self.events_streamed_before);
+        let dropped: u64 = counters.get(&RINGBUF_DROPPED, 0)?.iter().sum();
+        self.events_lost = dropped.saturating_sub(self.events_lost_before);
+
+        let forks_missed: PerCpuArray<_, u64> =
+            PerCpuArray::try_from(ebpf.map("FORKS_MISSED").unwrap())?;
+        self.forks_missed = forks_missed.get(&0, 0)?.iter().sum();
+
+        Ok(())
+    }
+
+    /// Drop the entries of exited threads from the in-kernel maps, so that a long running
+    /// session doesn't fill them up. Their measurements are gone after that.
+    /// Returns the measurements of the removed entries, as counted in `events`.
+    pub fn remove_exited(ebpf: &mut Ebpf) -> anyhow::Result<u64> {
+        let mut removed = 0;
+        let mut stats_map: PerCpuHashMap<_, StatKey, StatValue> =
+            PerCpuHashMap::try_from(ebpf.map_mut("STATS").unwrap())?;
+        let keys = stats_map.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in keys {
+            if procfs::thread_exists(key.pid, key.tid) {
+                continue;
+            }
+            // Removed concurrently is fine as well
+            let Ok(values) = stats_map.get(&key, 0) else {
+                continue;
+            };
+            if stats_map.remove(&key).is_ok() && key.kind != EventType::SyscallErrorStats as u32 {
+                removed += values.iter().map(|value| value.count).sum::<u64>();
+            }
+        }
+
+        let mut hist_map: PerCpuHashMap<_, HistKey, u64> =
+            PerCpuHashMap::try_from(ebpf.map_mut("HIST").unwrap())?;
+        let keys = hist_map.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in keys {
+            if !procfs::thread_exists(key.key.pid, key.key.tid) {
+                let _ = hist_map.remove(&key);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Start a new period: forget the measurements, keep what is known about the system.
+    pub fn reset(&mut self) {
+        self.processes.clear();
+        self.events = 0;
+        self.slow_events.clear();
+        self.events_streamed_before += self.events_streamed;
+        self.events_streamed = 0;
+        self.events_lost_before += self.events_lost;
+        self.events_lost = 0;
+    }
+
+    /// Share of the events of this period that were dropped, in percent.
+    pub fn events_lost_percent(&self) -> f64 {
+        let sent = self.events_streamed + self.events_lost;
+        if sent == 0 {
+            return 0.0;
+        }
+        self.events_lost as f64 * 100.0 / sent as f64
+    }
+
+    /// Events dropped since monitoring started, over all periods.
+    pub fn events_lost_total(&self) -> u64 {
+        self.events_lost_before + self.events_lost
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel_delay_common::syscall_error_id;
+
+    use super::*;
+
+    fn with_hist(samples: &[(usize, u64)], min_ns: u64, max_ns: u64) -> Stat {
+        let mut stat = Stat::default();
+        let count = samples.iter().map(|&(_, count)| count).sum();
+        stat.add(count, 0, min_ns, max_ns);
+        for &(slot, count) in samples {
+            stat.add_hist(slot, count);
+        }
+        stat
+    }
+
+    #[test]
+    fn percentile_of_empty_histogram_is_zero() {
+        assert_eq!(Stat::default().percentile(99.0, 0), 0);
+    }
+
+    #[test]
+    fn percentile_interpolates_within_a_single_slot() {
+        // Log2 slot 11 holds [1024, 2047]
+        let stat = with_hist(&[(11, 100)], 1000, 3000);
+        assert_eq!(stat.percentile(0.0, 0), 1034);
+        assert_eq!(stat.percentile(50.0, 0), 1535);
+        assert_eq!(stat.percentile(100.0, 0), 2047);
+
+        // Clamped to the exact min and max
+        let stat = with_hist(&[(11, 100)], 1500, 1600);
+        assert_eq!(stat.percentile(1.0, 0), 1500);
+        assert_eq!(stat.percentile(99.0, 0), 1600);
+    }
+
+    #[test]
+    fn percentile_in_last_linear_slot_is_bounded_by_max() {
+        // The last slot is open-ended, its upper bound is u64::MAX
+        let stat = with_hist(&[(1, 1), (HIST_SLOTS - 1, 1)], 1000, 5_000_000);
+        assert_eq!(stat.percentile(50.0, 1000), 1999);
+        assert_eq!(stat.percentile(99.0, 1000), 5_000_000);
+        assert_eq!(hist_slot(u64::MAX, 1000), HIST_SLOTS - 1);
+    }
+
+    #[test]
+    fn percentile_rank_is_not_rounded_up() {
+        // 99.9% of 1000 samples is the 999th one, still in the first slot
+        let stat = with_hist(&[(1, 999), (30, 1)], 1, 1 << 29);
+        assert_eq!(stat.percentile(99.9, 0), 1);
+        assert_eq!(stat.percentile(100.0, 0), 1 << 29);
+
+        let stat = with_hist(&[(5, 9_990), (20, 10)], 16, 1 << 19);
+        assert_eq!(stat.percentile(99.9, 0), 31);
+        assert_eq!(stat.percentile(99.91, 0), 1 << 19);
+    }
+
+    #[test]
+    fn syscall_errors_are_split_by_number_and_errno() {
+        let mut stats = Stats::default();
+        let value = StatValue {
+            count: 2,
+            total_ns: 100,
+            min_ns: 40,
+            max_ns: 60,
+        };
+        // openat failing with ENOENT, then with ERESTARTSYS
+        for id in [syscall_error_id(257, 2), syscall_error_id(257, 512)] {
+            let key = StatKey {
+                pid: 1,
+                tid: 2,
+                kind: EventType::SyscallErrorStats as u32,
+                id,
+            };
+            stats.add_stat(&key, &value);
+        }
+        // Numbers above 16 bits are truncated rather than mixed into the errno
+        let key = StatKey {
+            pid: 1,
+            tid: 2,
+            kind: EventType::SyscallErrorStats as u32,
+            id: syscall_error_id(0x1_0003, 11),
+        };
+        stats.add_stat(&key, &value);
+
+        let errors = &stats.processes[&1].threads[&2].syscall_errors;
+        let mut keys: Vec<_> = errors.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, [(3, 11), (257, 2), (257, 512)]);
+        assert_eq!(errors[&(257, 2)].count, 2);
+        // Counted in the syscall's own entry already
+        assert_eq!(stats.events, 0);
+    }
+}
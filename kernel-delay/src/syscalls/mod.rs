@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+mod aarch64;
+mod generic;
+mod riscv64;
+mod x86_64;
+
+// Syscall numbers mean different syscalls on every architecture: the tables are generated from
+// the kernel headers by build/gen-syscalls.py
+const TABLES: [&[(u32, &str)]; 4] = [
+    x86_64::SYSCALLS,
+    aarch64::SYSCALLS,
+    riscv64::SYSCALLS,
+    generic::SYSCALLS,
+];
+const UNSET: u8 = u8::MAX;
+
+// Index in TABLES of the architecture the syscall numbers come from, the host's unless set
+static TABLE: AtomicU8 = AtomicU8::new(UNSET);
+
+fn table_of(arch: &str) -> u8 {
+    match arch {
+        "x86_64" => 0,
+        "aarch64" => 1,
+        "riscv64" => 2,
+        _ => 3,
+    }
+}
+
+/// Name the syscall numbers of `arch` (as in `std::env::consts::ARCH`) from now on, e.g. those of
+/// a recording made on another host.
+pub fn set_arch(arch: &str) {
+    TABLE.store(table_of(arch), Ordering::Relaxed);
+}
+
+/// Name of a syscall by number, on the host architecture or the one given to `set_arch()`.
+pub fn name(number: u32) -> Option<&'static str> {
+    let table = match TABLE.load(Ordering::Relaxed) {
+        UNSET => table_of(std::env::consts::ARCH),
+        table => table,
+    };
+    let syscalls = TABLES[table as usize];
+    syscalls
+        .binary_search_by_key(&number, |&(number, _)| number)
+        .ok()
+        .map(|index| syscalls[index].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_are_sorted() {
+        // `name()` binary searches them
+        for table in TABLES {
+            assert!(table.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        }
+    }
+
+    #[test]
+    fn numbers_per_architecture() {
+        // The only test touching the global table, others would see the names change
+        set_arch("x86_64");
+        assert_eq!(name(0), Some("read"));
+        assert_eq!(name(257), Some("openat"));
+        assert_eq!(name(335), Some("uretprobe"));
+        assert_eq!(name(100_000), None);
+        set_arch("aarch64");
+        assert_eq!(name(56), Some("openat"));
+        assert_eq!(name(63), Some("read"));
+        set_arch("riscv64");
+        assert_eq!(name(259), Some("riscv_flush_icache"));
+        // Anything else uses the generic table, without the riscv64 syscalls
+        set_arch("loongarch64");
+        assert_eq!(name(56), Some("openat"));
+        assert_eq!(name(259), None);
+        TABLE.store(UNSET, Ordering::Relaxed);
+    }
+}
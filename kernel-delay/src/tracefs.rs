@@ -0,0 +1,285 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context as _, anyhow, bail};
+use kernel_delay_common::FieldOffsets;
+use log::debug;
+
+// tracefs is mounted on /sys/kernel/tracing on recent kernels and only under debugfs on older ones
+const TRACEFS_ROOTS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// A single `field:` line of a tracepoint format file.
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub signed: bool,
+}
+
+/// Parsed `/sys/kernel/tracing/events/<category>/<name>/format`.
+#[derive(Debug, Clone)]
+pub struct TracepointFormat {
+    pub category: String,
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl TracepointFormat {
+    pub fn load(category: &str, name: &str) -> anyhow::Result<Self> {
+        let path = format_path(category, name)?;
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(Self {
+            category: category.to_string(),
+            name: name.to_string(),
+            fields: parse_fields(&text),
+        })
+    }
+
+    pub fn field(&self, name: &str) -> anyhow::Result<&Field> {
+        self.fields.iter().find(|f| f.name == name).ok_or_else(|| {
+            anyhow!("tracepoint {}:{} has no field `{}`", self.category, self.name, name)
+        })
+    }
+
+    pub fn offset(&self, name: &str) -> anyhow::Result<u32> {
+        self.field(name).map(|f| f.offset)
+    }
+
+    /// Offset of an integer field the eBPF programs read as `size` bytes, checking that it has
+    /// that size and signedness on this kernel.
+    pub fn integer_offset(&self, name: &str, size: u32, signed: bool) -> anyhow::Result<u32> {
+        let field = self.field(name)?;
+        if field.size != size || field.signed != signed {
+            bail!(
+                "tracepoint {}:{} field `{}` is a {}{}-byte integer, expected {}{}",
+                self.category,
+                self.name,
+                name,
+                if field.signed { "signed " } else { "unsigned " },
+                field.size,
+                if signed { "signed " } else { "unsigned " },
+                size
+            );
+        }
+        Ok(field.offset)
+    }
+}
+
+fn format_path(category: &str, name: &str) -> anyhow::Result<PathBuf> {
+    TRACEFS_ROOTS
+        .iter()
+        .map(|root| PathBuf::from(root).join("events").join(category).join(name).join("format"))
+        .find(|path| path.exists())
+        .ok_or_else(|| anyhow!("tracepoint {category}:{name} not found, is tracefs mounted?"))
+}
+
+// Lines look like:
+//   field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+//   field:__data_loc char[] name;	offset:12;	size:4;	signed:0;
+//   field:char prev_comm[16];	offset:8;	size:16;	signed:0;
+fn parse_fields(text: &str) -> Vec<Field> {
+    let mut fields = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let Some(rest) = line.strip_prefix("field:") else {
+            continue;
+        };
+
+        let mut name = None;
+        let mut offset = None;
+        let mut size = None;
+        let mut signed = false;
+        for (i, part) in rest.split(';').map(str::trim).enumerate() {
+            if i == 0 {
+                // The declaration: the field name is the last word, minus any array suffix
+                name = part
+                    .split_whitespace()
+                    .last()
+                    .map(|word| word.split('[').next().unwrap_or(word).to_string());
+            } else if let Some(value) = part.strip_prefix("offset:") {
+                offset = value.parse().ok();
+            } else if let Some(value) = part.strip_prefix("size:") {
+                size = value.parse().ok();
+            } else if let Some(value) = part.strip_prefix("signed:") {
+                signed = value == "1";
+            }
+        }
+
+        if let (Some(name), Some(offset), Some(size)) = (name, offset, size) {
+            fields.push(Field {
+                name,
+                offset,
+                size,
+                signed,
+            });
+        }
+    }
+    fields
+}
+
+/// Resolve the offsets of every tracepoint field the eBPF programs read, so that they do not
+/// depend on the layout of one particular kernel version or architecture.
+pub fn resolve_field_offsets(syscalls: &[String]) -> anyhow::Result<FieldOffsets> {
+    let mut offsets = FieldOffsets::default();
+
+    if syscalls.is_empty() {
+        let format = TracepointFormat::load("raw_syscalls", "sys_exit")?;
+        // Both `long`: -1 for skipped syscalls and -errno for failed ones
+        offsets.raw_syscall_id = format.integer_offset("id", 8, true)?;
+        offsets.raw_syscall_ret = format.integer_offset("ret", 8, true)?;
+    } else {
+        // All per-syscall tracepoints share one layout, the first one that exists is enough
+        let format = syscalls
+            .iter()
+            .find_map(|name| TracepointFormat::load("syscalls", &format!("sys_exit_{name}")).ok())
+            .ok_or_else(|| anyhow!("none of the requested syscalls has a tracepoint"))?;
+        offsets.syscall_nr = format.integer_offset("__syscall_nr", 4, true)?;
+        offsets.syscall_ret = format.integer_offset("ret", 8, true)?;
+    }
+
+    let format = TracepointFormat::load("irq", "softirq_entry")?;
+    offsets.softirq_vec = format.integer_offset("vec", 4, false)?;
+
+    let format = TracepointFormat::load("irq", "irq_handler_entry")?;
+    offsets.irq_handler_irq = format.integer_offset("irq", 4, true)?;
+    // `__data_loc`: the offset of the string in the record in the low 16 bits, its length above
+    offsets.irq_handler_name = format.integer_offset("name", 4, false)?;
+
+    let format = TracepointFormat::load("sched", "sched_switch")?;
+    offsets.sched_prev_pid = format.integer_offset("prev_pid", 4, true)?;
+    offsets.sched_prev_state = prev_state_offset(&format)?;
+    offsets.sched_next_pid = format.integer_offset("next_pid", 4, true)?;
+
+    // sched_wakeup and sched_wakeup_new are both instances of the sched_wakeup_template class
+    let format = TracepointFormat::load("sched", "sched_wakeup")?;
+    offsets.sched_wakeup_pid = format.integer_offset("pid", 4, true)?;
+
+    // Unlike sched_process_fork, tells new threads (CLONE_THREAD) from new processes
+    let format = TracepointFormat::load("task", "task_newtask")?;
+    offsets.newtask_pid = format.integer_offset("pid", 4, true)?;
+    offsets.newtask_clone_flags = format.integer_offset("clone_flags", 8, false)?;
+    offsets.newtask_comm = format.offset("comm")?;
+
+    let format = TracepointFormat::load("sched", "sched_process_exit")?;
+    offsets.exit_pid = format.integer_offset("pid", 4, true)?;
+
+    // Only used to follow thread renames, names are still captured without it
+    match TracepointFormat::load("task", "task_rename") {
+        Ok(format) => {
+            if format.field("pid").is_ok() {
+                offsets.task_rename_pid = format.integer_offset("pid", 4, true)?;
+            }
+            offsets.task_rename_newcomm = format.offset("newcomm")?;
+        }
+        Err(e) => debug!("Thread renames are not followed: {:#}", e),
+    }
+
+    Ok(offsets)
+}
+
+// sched_switch `prev_state` is read as a u32, the state bits all fit in it. It is an `unsigned int`
+// on recent kernels and a `long` on older ones, whose low half is read then.
+fn prev_state_offset(format: &TracepointFormat) -> anyhow::Result<u32> {
+    if format.field("prev_state")?.size == 8 {
+        let offset = format.integer_offset("prev_state", 8, true)?;
+        return Ok(if cfg!(target_endian = "big") { offset + 4 } else { offset });
+    }
+    format.integer_offset("prev_state", 4, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // /sys/kernel/tracing/events/raw_syscalls/sys_exit/format of an x86_64 6.x kernel
+    const RAW_SYS_EXIT: &str = "name: sys_exit
+ID: 348
+format:
+	field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+	field:unsigned char common_flags;	offset:2;	size:1;	signed:0;
+	field:unsigned char common_preempt_count;	offset:3;	size:1;	signed:0;
+	field:int common_pid;	offset:4;	size:4;	signed:1;
+
+	field:long id;	offset:8;	size:8;	signed:1;
+	field:long ret;	offset:16;	size:8;	signed:1;
+
+print fmt: \"NR %ld = %ld\", REC->id, REC->ret
+";
+
+    fn format(text: &str) -> TracepointFormat {
+        TracepointFormat {
+            category: "raw_syscalls".to_string(),
+            name: "sys_exit".to_string(),
+            fields: parse_fields(text),
+        }
+    }
+
+    #[test]
+    fn parses_offset_size_and_signedness() {
+        let format = format(RAW_SYS_EXIT);
+        assert_eq!(format.fields.len(), 6);
+
+        let common_type = format.field("common_type").unwrap();
+        assert_eq!((common_type.offset, common_type.size), (0, 2));
+        assert!(!common_type.signed);
+
+        let ret = format.field("ret").unwrap();
+        assert_eq!((ret.offset, ret.size), (16, 8));
+        assert!(ret.signed);
+        assert_eq!(format.offset("id").unwrap(), 8);
+    }
+
+    #[test]
+    fn strips_array_suffixes_and_data_loc() {
+        let format = format(
+            "\tfield:char prev_comm[16];\toffset:8;\tsize:16;\tsigned:0;\n\
+             \tfield:__data_loc char[] name;\toffset:12;\tsize:4;\tsigned:0;\n",
+        );
+        let comm = format.field("prev_comm").unwrap();
+        assert_eq!((comm.offset, comm.size), (8, 16));
+        assert_eq!(format.offset("name").unwrap(), 12);
+    }
+
+    #[test]
+    fn skips_incomplete_lines() {
+        let format = format(
+            "\tfield:int no_offset;\tsize:4;\tsigned:1;\n\
+             \tfield:int no_size;\toffset:4;\tsigned:1;\n\
+             \tfield:int bad_offset;\toffset:x;\tsize:4;\tsigned:1;\n\
+             print fmt: \"field:int not_a_field;\toffset:0;\tsize:4;\"\n",
+        );
+        assert!(format.fields.is_empty());
+    }
+
+    #[test]
+    fn missing_field_is_an_error() {
+        let error = format(RAW_SYS_EXIT).offset("prev_pid").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tracepoint raw_syscalls:sys_exit has no field `prev_pid`"
+        );
+    }
+
+    #[test]
+    fn integer_offset_checks_size_and_signedness() {
+        let format = format(RAW_SYS_EXIT);
+        assert_eq!(format.integer_offset("ret", 8, true).unwrap(), 16);
+        assert!(format.integer_offset("ret", 4, true).is_err());
+        assert!(format.integer_offset("common_type", 2, true).is_err());
+        assert!(format.integer_offset("missing", 8, true).is_err());
+    }
+
+    #[test]
+    fn prev_state_as_long_or_unsigned_int() {
+        let long = format("\tfield:long prev_state;\toffset:32;\tsize:8;\tsigned:1;\n");
+        let low_half = if cfg!(target_endian = "big") { 36 } else { 32 };
+        assert_eq!(prev_state_offset(&long).unwrap(), low_half);
+
+        let uint = format("\tfield:unsigned int prev_state;\toffset:32;\tsize:4;\tsigned:0;\n");
+        assert_eq!(prev_state_offset(&uint).unwrap(), 32);
+
+        let short = format("\tfield:short prev_state;\toffset:32;\tsize:2;\tsigned:1;\n");
+        assert!(prev_state_offset(&short).is_err());
+    }
+}
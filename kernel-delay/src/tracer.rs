@@ -0,0 +1,319 @@
+use std::{
+    convert::TryFrom,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use aya::{
+    Ebpf, EbpfLoader,
+    maps::{Array, HashMap as AyaHashMap, MapData},
+    programs::TracePoint,
+};
+use kernel_delay_common::{
+    Config, FLAG_AGGREGATE, FLAG_ALL, FLAG_CGROUP, FLAG_FOLLOW_FORKS, FLAG_STACKS, FLAG_TRACE,
+};
+use log::{debug, warn};
+
+use crate::{Mode, TraceArgs, cgroup, procfs, tracefs};
+
+/// The eBPF programs, loaded, told about the targets and attached. They stay attached until
+/// this is dropped.
+pub struct Tracer {
+    pub ebpf: Ebpf,
+    /// Pids given on the command line and the launched command, without descendants
+    pub pids: Vec<u32>,
+    pub cgroup_dirs: Vec<PathBuf>,
+    cgroup_map: AyaHashMap<MapData, u64, u8>,
+    cgroup_scan: Instant,
+    pid_scan: Instant,
+}
+
+impl Tracer {
+    /// Load the eBPF programs, register the targets of `args` (plus the launched command
+    /// `child_pid`) and attach the programs. `ringbuf_size` overrides the size of the ring
+    /// buffer events are streamed through, and in aggregate mode syscalls, softirqs and run queue
+    /// waits of at least `slow_ns` (when non-zero) are streamed as well. With `stacks`, the
+    /// stacks of the slow syscalls and softirqs are captured into the STACKS map.
+    pub fn start(
+        args: &TraceArgs,
+        child_pid: Option<u32>,
+        mode: Mode,
+        ringbuf_size: Option<u32>,
+        slow_ns: u64,
+        stacks: bool,
+    ) -> anyhow::Result<Self> {
+        // Bump the memlock rlimit. This is needed for older kernels that don't use the
+        // new memcg based accounting, see https://lwn.net/Articles/837122/
+        let rlim = libc::rlimit {
+            rlim_cur: libc::RLIM_INFINITY,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+        if ret != 0 {
+            debug!("remove limit on locked memory failed, ret is: {ret}");
+        }
+
+        // This will include your eBPF object file as raw bytes at compile-time and load it at
+        // runtime. This approach is recommended for most real-world use cases. If you would
+        // like to specify the eBPF program at runtime rather than at compile-time, you can
+        // reach for `Bpf::load_file` instead.
+        let mut loader = EbpfLoader::new();
+        if let Some(size) = ringbuf_size {
+            loader.set_max_entries("RING_BUF", size);
+        }
+        let mut ebpf = loader.load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/kernel-delay"
+        )))?;
+        match aya_log::EbpfLogger::init(&mut ebpf) {
+            Err(e) => {
+                // This can happen if you remove all log statements from your eBPF program.
+                warn!("failed to initialize eBPF logger: {e}");
+            }
+            Ok(logger) => {
+                let mut logger =
+                    tokio::io::unix::AsyncFd::with_interest(logger, tokio::io::Interest::READABLE)?;
+                tokio::task::spawn(async move {
+                    loop {
+                        let mut guard = logger.readable_mut().await.unwrap();
+                        guard.get_inner_mut().flush();
+                        guard.clear_ready();
+                    }
+                });
+            }
+        }
+
+        // Collect the target processes, with their current descendants when following the tree
+        let mut pids = args.pids.clone();
+        pids.extend(child_pid);
+        let mut traced_pids = pids.clone();
+        if args.pid_tree {
+            traced_pids.extend(procfs::descendants(&args.pids));
+        }
+
+        let mut pid_map: AyaHashMap<_, u32, u8> =
+            AyaHashMap::try_from(ebpf.map_mut("TARGET_PIDS").unwrap())?;
+        for &pid in &traced_pids {
+            pid_map.insert(pid, 1, 0)?;
+        }
+
+        // Resolve the target cgroups, the eBPF programs match tasks against their IDs
+        let mut cgroup_dirs = Vec::new();
+        for path in &args.cgroups {
+            cgroup_dirs.push(cgroup::from_path(path)?);
+        }
+        for unit in &args.units {
+            cgroup_dirs.push(cgroup::from_unit(unit)?);
+        }
+        for id in &args.container_ids {
+            cgroup_dirs.push(cgroup::from_container_id(id)?);
+        }
+        let mut cgroup_map: AyaHashMap<_, u64, u8> =
+            AyaHashMap::try_from(ebpf.take_map("TARGET_CGROUPS").unwrap())?;
+        update_target_cgroups(&mut cgroup_map, &cgroup_dirs)?;
+
+        // Seed the threads that already exist, sched_switch can't tell their PID when switching
+        // in
+        let mut seed_pids = traced_pids;
+        for dir in cgroup_dirs.iter().flat_map(|dir| cgroup::tree(dir)) {
+            seed_pids.extend(cgroup::procs(&dir));
+        }
+        let mut tid_map: AyaHashMap<_, u32, u32> =
+            AyaHashMap::try_from(ebpf.map_mut("TARGET_TIDS").unwrap())?;
+        for &pid in &seed_pids {
+            for tid in procfs::thread_ids(pid) {
+                tid_map.insert(tid, pid, 0)?;
+            }
+        }
+
+        // Tell the eBPF programs where the tracepoint fields they read live on this kernel
+        let mut flags = match mode {
+            Mode::Aggregate => FLAG_AGGREGATE,
+            Mode::Trace => FLAG_TRACE,
+        };
+        if args.pid_tree {
+            flags |= FLAG_FOLLOW_FORKS;
+        }
+        if !cgroup_dirs.is_empty() {
+            flags |= FLAG_CGROUP;
+        }
+        if args.all {
+            flags |= FLAG_ALL;
+        }
+        if stacks {
+            flags |= FLAG_STACKS;
+        }
+        let config = Config {
+            offsets: tracefs::resolve_field_offsets(&args.syscalls)?,
+            flags,
+            exclude_pid: std::process::id(),
+            hist_step_ns: args.hist_linear.unwrap_or(0),
+            slow_ns,
+        };
+        debug!("Tracepoint field offsets: {:?}", config.offsets);
+        let mut config_map: Array<_, Config> = Array::try_from(ebpf.map_mut("CONFIG").unwrap())?;
+        config_map.set(0, config, 0)?;
+
+        attach_programs(&mut ebpf, &args.syscalls)?;
+
+        Ok(Self {
+            ebpf,
+            pids,
+            cgroup_dirs,
+            cgroup_map,
+            cgroup_scan: Instant::now(),
+            pid_scan: Instant::now(),
+        })
+    }
+
+    /// Pick up cgroups created since the last scan (e.g. by a restarted container), at most once
+    /// a second.
+    pub fn rescan_cgroups(&mut self) {
+        if self.cgroup_dirs.is_empty() || self.cgroup_scan.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        if let Err(e) = update_target_cgroups(&mut self.cgroup_map, &self.cgroup_dirs) {
+            warn!("Failed to update target cgroups: {}", e);
+        }
+        self.cgroup_scan = Instant::now();
+    }
+
+    /// Drop the target processes that exited from TARGET_PIDS, so that a later process reusing
+    /// the PID is not picked up, at most once a second. The eBPF programs can't tell when the
+    /// last thread of a process is gone.
+    pub fn forget_exited_pids(&mut self) {
+        if self.pid_scan.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.pid_scan = Instant::now();
+        let Ok(mut pid_map) =
+            AyaHashMap::<_, u32, u8>::try_from(self.ebpf.map_mut("TARGET_PIDS").unwrap())
+        else {
+            return;
+        };
+        let pids: Vec<u32> = pid_map.keys().flatten().collect();
+        for pid in pids {
+            if !procfs::process_exists(pid) {
+                let _ = pid_map.remove(&pid);
+            }
+        }
+    }
+}
+
+// Register the cgroups and all their current descendants
+fn update_target_cgroups(
+    map: &mut AyaHashMap<MapData, u64, u8>,
+    dirs: &[PathBuf],
+) -> anyhow::Result<()> {
+    for dir in dirs.iter().flat_map(|dir| cgroup::tree(dir)) {
+        // The cgroup may be removed between listing and stat'ing it
+        if let Ok(id) = cgroup::id(&dir) {
+            map.insert(id, 1, 0)?;
+        }
+    }
+    Ok(())
+}
+
+fn attach_programs(ebpf: &mut Ebpf, syscalls: &[String]) -> anyhow::Result<()> {
+    if syscalls.is_empty() {
+        // Attach to raw_syscalls so that every syscall the target makes is measured
+        let program: &mut TracePoint = ebpf.program_mut("raw_syscall_enter").unwrap().try_into()?;
+        program.load()?;
+        program.attach("raw_syscalls", "sys_enter")?;
+
+        let program: &mut TracePoint = ebpf.program_mut("raw_syscall_exit").unwrap().try_into()?;
+        program.load()?;
+        program.attach("raw_syscalls", "sys_exit")?;
+    } else {
+        // Attach only to the requested per-syscall tracepoints (only attach to ones that exist)
+        //
+        // new kernel syscall is openat , not open , check "mount | grep tracefs"
+        // kernel 6.1.0-38-arm64, 在旧的内核中叫 sys_enter_open 新的内核里 sys_enter_openat.
+        // ls /sys/kernel/tracing/events/syscalls| grep open
+        // sys_enter_fsopen
+        // sys_enter_mq_open
+        // sys_enter_openat
+        // sys_enter_openat2
+        // ...
+        let program: &mut TracePoint = ebpf.program_mut("syscall_enter").unwrap().try_into()?;
+        program.load()?;
+        for syscall in syscalls {
+            let name = format!("sys_enter_{syscall}");
+            match program.attach("syscalls", &name) {
+                Ok(_) => debug!("Successfully attached to syscalls:{}", name),
+                Err(e) => warn!("Failed to attach to syscalls:{}: {}", name, e),
+            }
+        }
+
+        let program: &mut TracePoint = ebpf.program_mut("syscall_exit").unwrap().try_into()?;
+        program.load()?;
+        for syscall in syscalls {
+            let name = format!("sys_exit_{syscall}");
+            match program.attach("syscalls", &name) {
+                Ok(_) => debug!("Successfully attached to syscalls:{}", name),
+                Err(e) => warn!("Failed to attach to syscalls:{}: {}", name, e),
+            }
+        }
+    }
+
+    // Attach to softirq tracepoints
+    let program: &mut TracePoint = ebpf.program_mut("softirq_entry").unwrap().try_into()?;
+    program.load()?;
+    match program.attach("irq", "softirq_entry") {
+        Ok(_) => debug!("Successfully attached to irq:softirq_entry"),
+        Err(e) => debug!("Failed to attach to irq:softirq_entry: {}", e),
+    }
+
+    let program: &mut TracePoint = ebpf.program_mut("softirq_exit").unwrap().try_into()?;
+    program.load()?;
+    match program.attach("irq", "softirq_exit") {
+        Ok(_) => debug!("Successfully attached to irq:softirq_exit"),
+        Err(e) => debug!("Failed to attach to irq:softirq_exit: {}", e),
+    }
+
+    // Attach to the scheduler to measure how long target threads stay on the CPU and, together
+    // with the wakeups below, how long they wait on the run queue
+    let program: &mut TracePoint = ebpf.program_mut("sched_switch").unwrap().try_into()?;
+    program.load()?;
+    program.attach("sched", "sched_switch")?;
+
+    // Attach to wakeups to measure how long runnable target threads wait for a CPU
+    let program: &mut TracePoint = ebpf.program_mut("sched_wakeup").unwrap().try_into()?;
+    program.load()?;
+    program.attach("sched", "sched_wakeup")?;
+    program.attach("sched", "sched_wakeup_new")?;
+
+    // Attach to task creation and exit to follow new threads and forks and forget exited threads
+    let program: &mut TracePoint = ebpf.program_mut("task_newtask").unwrap().try_into()?;
+    program.load()?;
+    program.attach("task", "task_newtask")?;
+
+    let program: &mut TracePoint = ebpf.program_mut("sched_process_exit").unwrap().try_into()?;
+    program.load()?;
+    program.attach("sched", "sched_process_exit")?;
+
+    // Attach to thread renames (prctl(PR_SET_NAME), exec, writes to comm) to keep names current
+    let program: &mut TracePoint = ebpf.program_mut("task_rename").unwrap().try_into()?;
+    program.load()?;
+    match program.attach("task", "task_rename") {
+        Ok(_) => debug!("Successfully attached to task:task_rename"),
+        Err(e) => debug!("Failed to attach to task:task_rename: {}", e),
+    }
+
+    // Attach to hard IRQ handler tracepoints
+    let program: &mut TracePoint = ebpf.program_mut("irq_handler_entry").unwrap().try_into()?;
+    program.load()?;
+    match program.attach("irq", "irq_handler_entry") {
+        Ok(_) => debug!("Successfully attached to irq:irq_handler_entry"),
+        Err(e) => debug!("Failed to attach to irq:irq_handler_entry: {}", e),
+    }
+
+    let program: &mut TracePoint = ebpf.program_mut("irq_handler_exit").unwrap().try_into()?;
+    program.load()?;
+    match program.attach("irq", "irq_handler_exit") {
+        Ok(_) => debug!("Successfully attached to irq:irq_handler_exit"),
+        Err(e) => debug!("Failed to attach to irq:irq_handler_exit: {}", e),
+    }
+
+    Ok(())
+}